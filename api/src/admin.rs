@@ -0,0 +1,144 @@
+use actix_web::{
+    delete, get,
+    web::{self, Data},
+    HttpResponse,
+};
+use pikav::publisher::SessionInfo;
+use serde_json::json;
+use twa_jwks::actix_web::JwtPayload;
+
+use crate::{client, error::ApiError, policy::Policy, Frame, JwtClaims, Publisher};
+
+#[get("/admin/sessions")]
+async fn list(
+    publisher: Data<Publisher<Frame>>,
+    nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
+    JwtPayload(payload): JwtPayload<JwtClaims>,
+) -> Result<HttpResponse, ApiError> {
+    if !policy.is_admin(&payload) {
+        return ApiError::Forbidden.into_response();
+    }
+
+    let sessions = list_sessions(&publisher, &nodes, None).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "sessions": sessions })))
+}
+
+#[get("/admin/users/{user_id}/sessions")]
+async fn list_user(
+    params: web::Path<(String,)>,
+    publisher: Data<Publisher<Frame>>,
+    nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
+    JwtPayload(payload): JwtPayload<JwtClaims>,
+) -> Result<HttpResponse, ApiError> {
+    if !policy.is_admin(&payload) {
+        return ApiError::Forbidden.into_response();
+    }
+
+    let sessions = list_sessions(&publisher, &nodes, Some(&params.0)).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "sessions": sessions })))
+}
+
+#[delete("/admin/sessions/{client_id}")]
+async fn disconnect(
+    params: web::Path<(String,)>,
+    publisher: Data<Publisher<Frame>>,
+    nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
+    JwtPayload(payload): JwtPayload<JwtClaims>,
+) -> Result<HttpResponse, ApiError> {
+    if !policy.is_admin(&payload) {
+        return ApiError::Forbidden.into_response();
+    }
+
+    let client_id = params.into_inner().0;
+
+    if publisher.disconnect(&client_id).await.is_ok() {
+        return Ok(HttpResponse::Ok().json(json!({ "success": true })));
+    }
+
+    // a session lives on a single node
+    for node in nodes.iter() {
+        let reply = node
+            .disconnect(client::DisconnectRequest {
+                client_id: client_id.to_owned(),
+            })
+            .await?;
+
+        if reply.into_inner().success {
+            return Ok(HttpResponse::Ok().json(json!({ "success": true })));
+        }
+    }
+
+    ApiError::NotFound.into_response()
+}
+
+/// Logs the user out everywhere, on this node and on every other one.
+#[delete("/admin/users/{user_id}/sessions")]
+async fn disconnect_user(
+    params: web::Path<(String,)>,
+    publisher: Data<Publisher<Frame>>,
+    nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
+    JwtPayload(payload): JwtPayload<JwtClaims>,
+) -> Result<HttpResponse, ApiError> {
+    if !policy.is_admin(&payload) {
+        return ApiError::Forbidden.into_response();
+    }
+
+    let user_id = params.into_inner().0;
+    let mut disconnected = publisher.disconnect_user(&user_id).await as u64;
+
+    for node in nodes.iter() {
+        let reply = node
+            .disconnect_user(client::DisconnectUserRequest {
+                user_id: user_id.to_owned(),
+            })
+            .await?;
+
+        disconnected += reply.into_inner().disconnected;
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "disconnected": disconnected })))
+}
+
+/// Sessions of this node and of every other node, oldest first.
+async fn list_sessions(
+    publisher: &Publisher<Frame>,
+    nodes: &[client::Client],
+    user_id: Option<&str>,
+) -> Result<Vec<SessionInfo>, ApiError> {
+    let mut sessions = match user_id {
+        Some(user_id) => publisher.user_sessions(user_id).await,
+        None => publisher.sessions().await,
+    };
+
+    for node in nodes.iter() {
+        let reply = node
+            .list_sessions(client::ListSessionsRequest {
+                user_id: user_id.map(str::to_owned),
+            })
+            .await?;
+
+        sessions.extend(
+            reply
+                .into_inner()
+                .sessions
+                .into_iter()
+                .map(|session| SessionInfo {
+                    id: session.id,
+                    user_id: session.user_id,
+                    filters: session.filters,
+                    created_at: session.created_at,
+                    queue_len: session.queue_len as usize,
+                }),
+        );
+    }
+
+    sessions.sort_by_key(|session| session.created_at);
+
+    Ok(sessions)
+}
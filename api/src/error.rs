@@ -9,6 +9,27 @@ pub enum ApiError {
 
     #[error("not found")]
     NotFound,
+
+    #[error("bad request: {0}")]
+    BadRequest(String),
+
+    #[error("conflict")]
+    Conflict,
+
+    #[error("unauthorized")]
+    Unauthorized,
+
+    #[error("forbidden")]
+    Forbidden,
+
+    #[error("session belongs to another user")]
+    SessionForbidden,
+
+    #[error("too many requests: {0}")]
+    TooManyRequests(String),
+
+    #[error("service unavailable")]
+    ServiceUnavailable,
 }
 
 impl ApiError {
@@ -22,6 +43,12 @@ impl ResponseError for ApiError {
         match *self {
             ApiError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Conflict => StatusCode::CONFLICT,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden | ApiError::SessionForbidden => StatusCode::FORBIDDEN,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
@@ -44,14 +71,53 @@ impl From<serde_json::Error> for ApiError {
     }
 }
 
+impl From<pikav::publisher::ParseError> for ApiError {
+    fn from(e: pikav::publisher::ParseError) -> Self {
+        ApiError::BadRequest(e.to_string())
+    }
+}
+
+impl From<pikav::TopicError> for ApiError {
+    fn from(e: pikav::TopicError) -> Self {
+        ApiError::BadRequest(e.to_string())
+    }
+}
+
 impl From<pikav::publisher::Error> for ApiError {
-    fn from(_: pikav::publisher::Error) -> Self {
-        ApiError::NotFound
+    fn from(e: pikav::publisher::Error) -> Self {
+        use pikav::publisher::Error;
+
+        match e {
+            Error::SessionNotFound => ApiError::NotFound,
+            Error::UserMismatch => ApiError::SessionForbidden,
+            Error::Full => ApiError::InternalServerError("Failed to create client".to_owned()),
+            Error::TooManySessions(max) => {
+                ApiError::TooManyRequests(format!("at most {max} sessions per user"))
+            }
+            Error::TooManyFilters(max) => {
+                ApiError::TooManyRequests(format!("at most {max} filters per session"))
+            }
+            Error::TooManySubscribes(max) => {
+                ApiError::TooManyRequests(format!("at most {max} subscribes per minute"))
+            }
+            Error::Draining => ApiError::ServiceUnavailable,
+            Error::Syntax(syntax) => {
+                ApiError::BadRequest(format!("only {syntax} filters are accepted"))
+            }
+        }
     }
 }
 
 impl From<pikav_client::Status> for ApiError {
     fn from(e: pikav_client::Status) -> Self {
-        ApiError::InternalServerError(e.to_string())
+        match e.code() {
+            pikav_client::Code::PermissionDenied => ApiError::SessionForbidden,
+            pikav_client::Code::InvalidArgument => ApiError::BadRequest(e.message().to_owned()),
+            pikav_client::Code::Unavailable => ApiError::ServiceUnavailable,
+            pikav_client::Code::ResourceExhausted => {
+                ApiError::TooManyRequests(e.message().to_owned())
+            }
+            _ => ApiError::InternalServerError(e.to_string()),
+        }
     }
 }
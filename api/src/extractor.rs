@@ -1,5 +1,12 @@
-use actix_web::{error::ErrorBadRequest, Error as ActixError, FromRequest};
-use futures_util::future::{err, ok, Ready};
+use actix_web::{
+    error::ErrorBadRequest,
+    http::header,
+    web::{Data, Query},
+    Error as ActixError, FromRequest,
+};
+use futures_util::future::{err, ok, LocalBoxFuture, Ready};
+use serde::{de::DeserializeOwned, Deserialize};
+use twa_jwks::{actix_web::JwtPayload, JwksClient};
 
 pub struct Client(pub String);
 
@@ -21,3 +28,86 @@ impl FromRequest for Client {
         }
     }
 }
+
+/// `Last-Event-ID` header, or `last_event_id` query parameter for clients
+/// opening a new connection themselves.
+pub struct LastEventId(pub Option<u64>);
+
+#[derive(Deserialize)]
+struct LastEventIdQuery {
+    last_event_id: Option<u64>,
+}
+
+impl FromRequest for LastEventId {
+    type Error = ActixError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let id = req
+            .headers()
+            .get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .or_else(|| {
+                Query::<LastEventIdQuery>::from_query(req.query_string())
+                    .ok()
+                    .and_then(|query| query.last_event_id)
+            });
+
+        ok(Self(id))
+    }
+}
+
+/// Payload of the bearer token from the `Authorization` header, or from the
+/// `access_token` query parameter since browsers can't set headers when
+/// opening a WebSocket.
+pub struct TokenPayload<T>(pub T);
+
+#[derive(Deserialize)]
+struct AccessTokenQuery {
+    access_token: Option<String>,
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for TokenPayload<T> {
+    type Error = ActixError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        if req.headers().contains_key(header::AUTHORIZATION) {
+            let payload = JwtPayload::<T>::from_request(req, payload);
+
+            return Box::pin(async move { payload.await.map(|JwtPayload(payload)| Self(payload)) });
+        }
+
+        let token = Query::<AccessTokenQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().access_token);
+
+        let client = req
+            .app_data::<Data<JwksClient>>()
+            .expect("JwksClient not found in app data")
+            .clone();
+
+        Box::pin(async move {
+            let token = match token {
+                Some(token) => token,
+                _ => {
+                    return Err(ErrorBadRequest(
+                        "authorization is missing from header and query",
+                    ))
+                }
+            };
+
+            let jwt = client.verify(&token).await.map_err(twa_jwks::Error::from)?;
+            let payload = jwt.payload().into::<T>().map_err(twa_jwks::Error::from)?;
+
+            Ok(Self(payload))
+        })
+    }
+}
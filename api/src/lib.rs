@@ -1,11 +1,18 @@
+mod admin;
 mod error;
 
 pub mod extractor;
+pub mod policy;
+mod poll;
+mod publish;
+mod webhook;
+mod ws;
 
-use std::io::Error;
+use std::{collections::VecDeque, future::Future, io::Error, sync::Arc};
 
 use actix_cors::Cors;
 use actix_web::{
+    dev::Server,
     error::ErrorInternalServerError,
     get,
     middleware::Condition,
@@ -15,123 +22,266 @@ use actix_web::{
 };
 use client::{SubscribeRequest, UnsubscribeRequest};
 use error::ApiError;
-use extractor::Client as ReqClient;
+use extractor::{Client as ReqClient, LastEventId, TokenPayload};
 use futures_core::Stream;
+use pikav::{
+    publisher::{Error as PublisherError, Predicate},
+    Topic, TopicFilter,
+};
+use policy::{AllowAll, Policy};
+use poll::Polls;
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Map, Value};
 use twa_jwks::{actix_web::JwtPayload, JwksClient};
+pub use webhook::AppWebhook;
+use webhook::Webhooks;
 
-pub use pikav::publisher::{Publisher, Receiver};
+pub use pikav::publisher::{ClientOptions, Frame, Publisher, Receiver};
 pub use pikav_client as client;
 
 #[derive(Deserialize)]
-struct JwtClaims {
+pub struct JwtClaims {
     pub sub: String,
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+/// `predicate` is a condition on the name and data of the events, see
+/// `pikav::publisher::Predicate`.
+#[derive(Deserialize)]
+struct SubscribeQuery {
+    predicate: Option<String>,
 }
 
 #[put(r"/subscribe/{filter:.*}")]
 async fn subscribe(
     params: web::Path<(String,)>,
-    publisher: Data<Publisher<Bytes>>,
+    query: web::Query<SubscribeQuery>,
+    publisher: Data<Publisher<Frame>>,
     client: ReqClient,
     nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
     JwtPayload(payload): JwtPayload<JwtClaims>,
 ) -> Result<HttpResponse, ApiError> {
-    let params = params.into_inner();
-
-    publisher
-        .subscribe(params.0.to_owned(), &payload.sub, &client.0)
-        .await
-        .ok();
+    let filter = params.into_inner().0.parse::<TopicFilter>()?;
 
-    for node in nodes.iter().filter(|n| n.same_region) {
-        node.subscribe(SubscribeRequest {
-            filter: params.0.to_owned(),
-            client_id: client.0.to_owned(),
-            user_id: payload.sub.to_owned(),
-        })
-        .await?;
+    if !policy.can_subscribe(filter.as_str(), &payload) {
+        return ApiError::Forbidden.into_response();
     }
 
+    subscribe_client(
+        &publisher,
+        &nodes,
+        &filter,
+        query.predicate.as_deref(),
+        &policy.audiences(&payload),
+        &payload.sub,
+        &client.0,
+    )
+    .await?;
+
     Ok(HttpResponse::Ok().json(json! ({ "success": true })))
 }
 
 #[put(r"/unsubscribe/{filter:.*}")]
 async fn unsubscribe(
     params: web::Path<(String,)>,
-    publisher: Data<Publisher<Bytes>>,
+    publisher: Data<Publisher<Frame>>,
     client: ReqClient,
     JwtPayload(payload): JwtPayload<JwtClaims>,
     nodes: Data<Vec<client::Client>>,
 ) -> Result<HttpResponse, ApiError> {
-    let params = params.into_inner();
+    let filter = params.into_inner().0.parse::<TopicFilter>()?;
 
-    publisher
-        .unsubscribe(params.0.to_owned(), &payload.sub, &client.0)
-        .await
-        .ok();
+    unsubscribe_client(&publisher, &nodes, &filter, &payload.sub, &client.0).await?;
 
-    for node in nodes.iter().filter(|n| n.same_region) {
-        node.unsubscribe(UnsubscribeRequest {
-            filter: params.0.to_owned(),
-            client_id: client.0.to_owned(),
-            user_id: payload.sub.to_owned(),
-        })
-        .await?;
+    Ok(HttpResponse::Ok().json(json! ({ "success": true })))
+}
+
+/// Users subscribed to `topic` on this node or on any other one.
+#[get(r"/presence/{topic:.*}")]
+async fn presence(
+    params: web::Path<(String,)>,
+    publisher: Data<Publisher<Frame>>,
+    nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
+    JwtPayload(payload): JwtPayload<JwtClaims>,
+) -> Result<HttpResponse, ApiError> {
+    let topic = params.into_inner().0.parse::<Topic>()?;
+
+    if !policy.can_subscribe(topic.as_str(), &payload) {
+        return ApiError::Forbidden.into_response();
     }
 
-    Ok(HttpResponse::Ok().json(json! ({ "success": true })))
+    let mut users = publisher.presence(topic.as_str()).await;
+
+    for node in nodes.iter() {
+        let reply = node
+            .presence(client::PresenceRequest {
+                topic: topic.to_string(),
+            })
+            .await?;
+
+        users.extend(reply.into_inner().users);
+    }
+
+    users.sort();
+    users.dedup();
+
+    Ok(HttpResponse::Ok().json(json! ({ "users": users })))
+}
+
+/// `resume_token` comes from the metadata of the `$SYS/session` event of a
+/// previous connection, resuming a session bound to a user needs its token
+/// too, e.g. the `access_token` query parameter. A token in the URL ends up in
+/// proxy and access logs, it should be short-lived and query strings should
+/// not be logged.
+#[derive(Deserialize)]
+struct EventsQuery {
+    resume_token: Option<String>,
 }
 
 #[get("/events")]
-async fn events(publisher: Data<Publisher<Bytes>>) -> Result<HttpResponse, ApiError> {
-    let rx = match publisher.create_client(true).await {
-        Some((rx, _)) => rx,
-        _ => {
-            return ApiError::InternalServerError("Failed to create client".to_owned())
-                .into_response()
-        }
-    };
+async fn events(
+    query: web::Query<EventsQuery>,
+    publisher: Data<Publisher<Frame>>,
+    LastEventId(last_event_id): LastEventId,
+    token: Option<TokenPayload<JwtClaims>>,
+) -> Result<HttpResponse, ApiError> {
+    let (rx, _) = publisher
+        .create_client(ClientOptions {
+            send_id: true,
+            last_event_id,
+            user_id: token.map(|TokenPayload(payload)| payload.sub),
+            resume_token: query.into_inner().resume_token,
+            ..Default::default()
+        })
+        .await?;
 
     Ok(HttpResponse::Ok()
         .append_header(("Content-Type", "text/event-stream"))
-        .streaming(Client(rx)))
+        .streaming(Client::new(rx)))
 }
 
 #[get("/events/{filter:.*}")]
 async fn events_subscribe(
     params: web::Path<(String,)>,
-    publisher: Data<Publisher<Bytes>>,
+    query: web::Query<SubscribeQuery>,
+    publisher: Data<Publisher<Frame>>,
     nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
     JwtPayload(payload): JwtPayload<JwtClaims>,
+    LastEventId(last_event_id): LastEventId,
 ) -> Result<HttpResponse, ApiError> {
-    let (rx, id) = match publisher.create_client(true).await {
-        Some(rx) => rx,
-        _ => {
-            return ApiError::InternalServerError("Failed to create client".to_owned())
-                .into_response()
-        }
-    };
+    let filter = params.into_inner().0.parse::<TopicFilter>()?;
+
+    if !policy.can_subscribe(filter.as_str(), &payload) {
+        return ApiError::Forbidden.into_response();
+    }
 
-    let params = params.into_inner();
+    // fails before creating a session that would never be subscribed
+    if let Some(predicate) = &query.predicate {
+        predicate.parse::<Predicate>()?;
+    }
+
+    let audiences = policy.audiences(&payload);
+
+    let (rx, id) = publisher
+        .create_client(ClientOptions {
+            send_id: true,
+            last_event_id,
+            user_id: Some(payload.sub.to_owned()),
+            audiences: audiences.to_owned(),
+            ..Default::default()
+        })
+        .await?;
+
+    subscribe_client(
+        &publisher,
+        &nodes,
+        &filter,
+        query.predicate.as_deref(),
+        &audiences,
+        &payload.sub,
+        &id,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok()
+        .append_header(("Content-Type", "text/event-stream"))
+        .streaming(Client::new(rx)))
+}
+
+/// Subscribes the session here and on the nodes of the same region, the
+/// session lives on one of them. Its audiences are refreshed from the claims.
+async fn subscribe_client(
+    publisher: &Publisher<Frame>,
+    nodes: &[client::Client],
+    filter: &TopicFilter,
+    predicate: Option<&str>,
+    audiences: &[String],
+    user_id: &str,
+    client_id: &str,
+) -> Result<(), ApiError> {
+    publisher
+        .subscribe_with_predicate(
+            filter.to_owned(),
+            predicate.map(str::parse).transpose()?,
+            user_id,
+            client_id,
+        )
+        .await
+        .or_else(ignore_session_not_found)?;
 
     publisher
-        .subscribe(params.0.to_owned(), &payload.sub, &id)
+        .set_audiences(audiences.to_vec(), user_id, client_id)
         .await
-        .ok();
+        .or_else(ignore_session_not_found)?;
 
     for node in nodes.iter().filter(|n| n.same_region) {
         node.subscribe(SubscribeRequest {
-            filter: params.0.to_owned(),
-            client_id: id.to_owned(),
-            user_id: payload.sub.to_owned(),
+            filter: filter.to_string(),
+            client_id: client_id.to_owned(),
+            user_id: user_id.to_owned(),
+            predicate: predicate.unwrap_or_default().to_owned(),
+            audiences: audiences.to_vec(),
         })
         .await?;
     }
 
-    Ok(HttpResponse::Ok()
-        .append_header(("Content-Type", "text/event-stream"))
-        .streaming(Client(rx)))
+    Ok(())
+}
+
+async fn unsubscribe_client(
+    publisher: &Publisher<Frame>,
+    nodes: &[client::Client],
+    filter: &TopicFilter,
+    user_id: &str,
+    client_id: &str,
+) -> Result<(), ApiError> {
+    publisher
+        .unsubscribe(filter.to_owned(), user_id, client_id)
+        .await
+        .or_else(ignore_session_not_found)?;
+
+    for node in nodes.iter().filter(|n| n.same_region) {
+        node.unsubscribe(UnsubscribeRequest {
+            filter: filter.to_string(),
+            client_id: client_id.to_owned(),
+            user_id: user_id.to_owned(),
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// The session may live on another node of the region.
+fn ignore_session_not_found(e: PublisherError) -> Result<(), PublisherError> {
+    match e {
+        PublisherError::SessionNotFound => Ok(()),
+        e => Err(e),
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -144,12 +294,33 @@ pub struct AppJwks {
     pub url: String,
 }
 
+/// Filter templates users are allowed to subscribe to, e.g.
+/// `users/{sub}/**` or `orgs/{claims.org}/**`, and topic templates they are
+/// allowed to publish to through `/publish`, to themselves only unless they
+/// are admins. `admins` are the users (`sub`) allowed to use `/admin`, nobody
+/// is without an ACL. `audiences` are the
+/// claims, e.g. `org` or `roles`, putting sessions in audiences.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppAcl {
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub publish: Vec<String>,
+    #[serde(default)]
+    pub admins: Vec<String>,
+    #[serde(default)]
+    pub audiences: Vec<String>,
+}
+
 pub struct AppOptions {
     pub listen: String,
     pub jwks: Option<AppJwks>,
     pub cors: Option<AppCors>,
-    pub publisher: Publisher<Bytes>,
+    pub publisher: Publisher<Frame>,
     pub nodes: Vec<client::Client>,
+    /// Subscribe authorization, every filter is allowed and publishing is not
+    /// when none is set.
+    pub policy: Option<Arc<dyn Policy>>,
+    pub webhooks: Vec<AppWebhook>,
 }
 
 pub struct App {
@@ -162,6 +333,27 @@ impl App {
     }
 
     pub async fn run(&self) -> std::io::Result<()> {
+        self.server(true).await?.await
+    }
+
+    /// Runs without handling signals until `shutdown` completes, then stops
+    /// gracefully.
+    pub async fn run_until(
+        &self,
+        shutdown: impl Future<Output = ()> + 'static,
+    ) -> std::io::Result<()> {
+        let server = self.server(false).await?;
+        let handle = server.handle();
+
+        actix_web::rt::spawn(async move {
+            shutdown.await;
+            handle.stop(true).await;
+        });
+
+        server.await
+    }
+
+    async fn server(&self, signals: bool) -> std::io::Result<Server> {
         let publisher = self.options.publisher.clone();
 
         let cors_permissive = self
@@ -178,29 +370,68 @@ impl App {
 
         let nodes = self.options.nodes.clone();
 
+        let policy = self
+            .options
+            .policy
+            .clone()
+            .unwrap_or_else(|| Arc::new(AllowAll));
+
+        let polls = Polls::default();
+        let webhooks = Data::new(Webhooks::new(self.options.webhooks.clone()));
+
+        actix_web::rt::spawn(polls.clone().remove_expired());
+
         println!(
             "Pikav api server listening on {}",
             self.options.listen.to_owned()
         );
 
-        HttpServer::new(move || {
+        let server = HttpServer::new(move || {
             ActixApp::new()
                 .app_data(Data::new(publisher.clone()))
                 .app_data(Data::new(jwks_client.clone()))
                 .app_data(Data::new(nodes.clone()))
+                .app_data(Data::from(policy.clone()))
+                .app_data(Data::new(polls.clone()))
+                .app_data(webhooks.clone())
                 .wrap(Condition::new(cors_permissive, Cors::permissive()))
                 .service(subscribe)
                 .service(unsubscribe)
+                .service(presence)
                 .service(events)
                 .service(events_subscribe)
-        })
-        .bind(self.options.listen.to_owned())?
-        .run()
-        .await
+                .service(ws::ws)
+                .service(poll::poll)
+                .service(publish::publish)
+                .service(webhook::webhook)
+                .service(admin::list)
+                .service(admin::list_user)
+                .service(admin::disconnect)
+                .service(admin::disconnect_user)
+        });
+
+        let server = match signals {
+            true => server,
+            false => server.disable_signals(),
+        };
+
+        Ok(server.bind(self.options.listen.to_owned())?.run())
     }
 }
 
-pub struct Client(Receiver<Bytes>);
+pub struct Client {
+    rx: Receiver<Frame>,
+    parts: VecDeque<Bytes>,
+}
+
+impl Client {
+    pub fn new(rx: Receiver<Frame>) -> Self {
+        Self {
+            rx,
+            parts: VecDeque::new(),
+        }
+    }
+}
 
 impl Stream for Client {
     type Item = Result<Bytes, ActixError>;
@@ -209,8 +440,19 @@ impl Stream for Client {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.0
-            .poll_recv(cx)
-            .map(|res| Some(res.ok_or_else(|| ErrorInternalServerError(""))))
+        if let Some(part) = self.parts.pop_front() {
+            return std::task::Poll::Ready(Some(Ok(part)));
+        }
+
+        self.rx.poll_recv(cx).map(|res| match res {
+            Some(frame) => {
+                let mut parts = frame.into_parts().into_iter();
+                let part = parts.next().unwrap_or_default();
+                self.parts.extend(parts);
+
+                Some(Ok(part))
+            }
+            None => Some(Err(ErrorInternalServerError(""))),
+        })
     }
 }
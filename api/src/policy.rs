@@ -0,0 +1,302 @@
+use glob_match::glob_match;
+use serde_json::Value;
+
+use crate::{AppAcl, JwtClaims};
+
+/// Decides whether a user can subscribe to a filter or publish to a topic.
+pub trait Policy: Send + Sync {
+    fn can_subscribe(&self, filter: &str, claims: &JwtClaims) -> bool;
+
+    fn can_publish(&self, _topic: &str, _claims: &JwtClaims) -> bool {
+        false
+    }
+
+    /// Whether the user can publish to other users, audiences or sessions,
+    /// otherwise `user_id` must be their own `sub`.
+    fn can_broadcast(&self, claims: &JwtClaims) -> bool {
+        self.is_admin(claims)
+    }
+
+    /// Admins can list and disconnect the sessions of every user.
+    fn is_admin(&self, _claims: &JwtClaims) -> bool {
+        false
+    }
+
+    /// Groups the sessions of the user are in, events can be published to
+    /// them through `audiences`.
+    fn audiences(&self, _claims: &JwtClaims) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+pub struct AllowAll;
+
+impl Policy for AllowAll {
+    fn can_subscribe(&self, _filter: &str, _claims: &JwtClaims) -> bool {
+        true
+    }
+}
+
+/// A filter is allowed when one of the `allow` templates, once its
+/// placeholders are replaced by the JWT claims, covers every topic it
+/// matches. Templates and filters are compared segment by segment, a
+/// wildcard in the filter needs the same or a broader one in the template:
+/// `users/{sub}/**` allows `users/42/todos/*` but not `users/*/todos/*`, and
+/// `docs/*` does not allow `docs/**` nor `docs/#`. MQTT wildcards are
+/// compared like glob ones, `+` like `*` and `#` like `**`, and `$` topics are
+/// only allowed by templates starting with `$`.
+impl Policy for AppAcl {
+    fn can_subscribe(&self, filter: &str, claims: &JwtClaims) -> bool {
+        is_allowed(&self.allow, filter, claims)
+    }
+
+    fn can_publish(&self, topic: &str, claims: &JwtClaims) -> bool {
+        is_allowed(&self.publish, topic, claims)
+    }
+
+    fn is_admin(&self, claims: &JwtClaims) -> bool {
+        self.admins.contains(&claims.sub)
+    }
+
+    /// `<claim>:<value>` for each value of the `audiences` claims, e.g.
+    /// `org:acme` or `roles:admin`.
+    fn audiences(&self, claims: &JwtClaims) -> Vec<String> {
+        self.audiences
+            .iter()
+            .flat_map(|claim| {
+                claim_values(claims, claim)
+                    .into_iter()
+                    .map(move |value| format!("{claim}:{value}"))
+            })
+            .collect()
+    }
+}
+
+fn is_allowed(templates: &[String], value: &str, claims: &JwtClaims) -> bool {
+    templates
+        .iter()
+        .flat_map(|template| expand(template, claims))
+        .any(|pattern| {
+            (pattern.starts_with('$') || !value.starts_with('$'))
+                && covers(
+                    &pattern.split('/').collect::<Vec<_>>(),
+                    &value.split('/').collect::<Vec<_>>(),
+                )
+        })
+}
+
+/// Whether every topic matched by `filter` is matched by `pattern`.
+fn covers(pattern: &[&str], filter: &[&str]) -> bool {
+    match (pattern.split_first(), filter.split_first()) {
+        (None, None) => true,
+        // last segment, matches its parent and every level below
+        (Some((&"#", _)), _) => true,
+        (Some((&"**", rest)), _) => {
+            // a trailing `**` needs at least one segment like `glob_match`
+            // does, a trailing `#` in the filter also matches its parent
+            let min = usize::from(rest.is_empty());
+
+            (min..=filter.len())
+                .any(|i| !(rest.is_empty() && filter[..i] == ["#"]) && covers(rest, &filter[i..]))
+        }
+        (Some((p, pattern)), Some((f, filter))) => {
+            let covered = match (*p, *f) {
+                (_, "**" | "#") => false,
+                ("*" | "+", _) => true,
+                (_, "*" | "+") => false,
+                (p, f) if f.contains(GLOB) => p == f,
+                (p, f) => glob_match(p, f),
+            };
+
+            covered && covers(pattern, filter)
+        }
+        _ => false,
+    }
+}
+
+/// Replaces `{sub}` and `{claims.<path>}` placeholders, an array claim
+/// produces one pattern per value. A placeholder with a missing claim, or a
+/// value that would change the pattern structure, produces no pattern.
+fn expand(template: &str, claims: &JwtClaims) -> Vec<String> {
+    let mut patterns = vec![String::new()];
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+
+        let key = &rest[start + 1..start + len];
+
+        let values: Vec<String> = match key {
+            "sub" => Some(claims.sub.to_owned())
+                .filter(|v| is_safe(v))
+                .into_iter()
+                .collect(),
+            _ => match key.strip_prefix("claims.") {
+                Some(path) => claim_values(claims, path),
+                // glob alternatives like `{a,b}` are kept as is
+                None => vec![rest[start..=start + len].to_owned()],
+            },
+        };
+
+        if values.is_empty() {
+            return Vec::new();
+        }
+
+        patterns = patterns
+            .iter()
+            .flat_map(|pattern| {
+                values
+                    .iter()
+                    .map(move |value| format!("{pattern}{}{value}", &rest[..start]))
+            })
+            .collect();
+
+        rest = &rest[start + len + 1..];
+    }
+
+    patterns.into_iter().map(|pattern| pattern + rest).collect()
+}
+
+fn claim_values(claims: &JwtClaims, path: &str) -> Vec<String> {
+    let mut keys = path.split('.');
+
+    let Some(mut value) = keys.next().and_then(|key| claims.claims.get(key)) else {
+        return Vec::new();
+    };
+
+    for key in keys {
+        match value.get(key) {
+            Some(v) => value = v,
+            None => return Vec::new(),
+        }
+    }
+
+    let values = match value {
+        Value::Array(values) => values.iter().filter_map(scalar).collect::<Vec<_>>(),
+        value => scalar(value).into_iter().collect(),
+    };
+
+    values.into_iter().filter(|v| is_safe(v)).collect()
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(v) => Some(v.to_owned()),
+        Value::Number(v) => Some(v.to_string()),
+        Value::Bool(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+const GLOB: [char; 8] = ['*', '?', '[', ']', '{', '}', '\\', '!'];
+
+/// A claim value can't be a wildcard segment nor start a `$` topic.
+fn is_safe(value: &str) -> bool {
+    !value.is_empty()
+        && !value.contains(['/', ','])
+        && !value.contains(GLOB)
+        && !matches!(value, "+" | "#")
+        && !value.starts_with('$')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn claims(sub: &str, claims: Value) -> JwtClaims {
+        JwtClaims {
+            sub: sub.to_owned(),
+            claims: serde_json::from_value(claims).unwrap(),
+        }
+    }
+
+    fn allows(template: &str, filter: &str) -> bool {
+        is_allowed(&[template.to_owned()], filter, &claims("42", json!({})))
+    }
+
+    #[test]
+    fn user_templates() {
+        let template = "users/{sub}/**";
+
+        assert!(allows(template, "users/42/todos"));
+        assert!(allows(template, "users/42/todos/*"));
+        assert!(allows(template, "users/42/todos/#"));
+        // `#` also matches `users/42`, `**` does not
+        assert!(!allows(template, "users/42/#"));
+        assert!(!allows(template, "users/*/todos/*"));
+        assert!(!allows(template, "users/+/todos/+"));
+        assert!(!allows(template, "users/43/todos"));
+        assert!(!allows(template, "users/42"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(allows("docs/*", "docs/1"));
+        assert!(allows("docs/*", "docs/*"));
+        assert!(allows("docs/*", "docs/+"));
+        assert!(!allows("docs/*", "docs/**"));
+        assert!(!allows("docs/*", "docs/#"));
+        assert!(!allows("docs/*", "docs/1/2"));
+
+        assert!(allows("docs/**", "docs/*/comments"));
+        assert!(allows("docs/**", "docs/1/#"));
+        assert!(!allows("docs/**", "docs/#"));
+        assert!(allows("docs/#", "docs/**"));
+        assert!(allows("docs/#", "docs"));
+        assert!(!allows("docs/**", "docs"));
+        assert!(!allows("docs/+", "docs/**"));
+    }
+
+    #[test]
+    fn sys() {
+        assert!(!allows("**", "$SYS/presence/docs"));
+        assert!(!allows("+/presence/docs", "$SYS/presence/docs"));
+        assert!(!allows("#", "$SYS/#"));
+        assert!(allows("$SYS/presence/**", "$SYS/presence/docs"));
+        assert!(allows("$SYS/presence/#", "$SYS/presence/+"));
+    }
+
+    #[test]
+    fn unsafe_claims() {
+        for value in [
+            "*", "**", "+", "#", "$SYS", "a/b", "a,b", "{a,b}", "[a]", "",
+        ] {
+            let claims = claims(value, json!({ "org": value }));
+
+            assert!(expand("users/{sub}/**", &claims).is_empty(), "{value}");
+            assert!(
+                expand("orgs/{claims.org}/**", &claims).is_empty(),
+                "{value}"
+            );
+        }
+
+        // glob alternatives of the template itself are kept
+        assert_eq!(
+            expand("docs/{a,b}/{sub}", &claims("42", json!({}))),
+            ["docs/{a,b}/42"]
+        );
+    }
+
+    #[test]
+    fn array_claims() {
+        let claims = claims(
+            "42",
+            json!({ "orgs": ["acme", "+", 7, {"id": "x"}], "team": { "id": "red" } }),
+        );
+
+        assert_eq!(
+            expand("orgs/{claims.orgs}/{claims.team.id}", &claims),
+            ["orgs/acme/red", "orgs/7/red"]
+        );
+        assert!(expand("orgs/{claims.missing}", &claims).is_empty());
+
+        let templates = ["orgs/{claims.orgs}/**".to_owned()];
+        assert!(is_allowed(&templates, "orgs/acme/docs", &claims));
+        assert!(is_allowed(&templates, "orgs/7/docs/+", &claims));
+        assert!(!is_allowed(&templates, "orgs/+/docs", &claims));
+        assert!(!is_allowed(&templates, "orgs/x/docs", &claims));
+    }
+}
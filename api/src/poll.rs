@@ -0,0 +1,206 @@
+use actix_web::{
+    get,
+    rt::time::{interval, timeout, Instant},
+    web::Data,
+    HttpResponse,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use twa_jwks::actix_web::JwtPayload;
+
+use crate::{
+    error::ApiError,
+    extractor::{Client as ReqClient, LastEventId},
+    policy::Policy,
+    ClientOptions, Frame, JwtClaims, Publisher, Receiver,
+};
+
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_BATCH: usize = 100;
+
+struct Session {
+    user_id: String,
+    rx: Option<Receiver<Frame>>,
+    last_seen: Instant,
+}
+
+/// Receivers of long-poll sessions kept between two polls, a session not
+/// polled for a minute is dropped and its client removed from the publisher.
+#[derive(Clone, Default)]
+pub struct Polls(Arc<Mutex<HashMap<String, Session>>>);
+
+impl Polls {
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn take(&self, client_id: &str, user_id: &str) -> Result<Receiver<Frame>, ApiError> {
+        let mut sessions = self.sessions();
+
+        let Some(session) = sessions.get_mut(client_id) else {
+            return Err(ApiError::NotFound);
+        };
+
+        if session.user_id != user_id {
+            return Err(ApiError::SessionForbidden);
+        }
+
+        let rx = session.rx.take().ok_or(ApiError::Conflict)?;
+        session.last_seen = Instant::now();
+
+        Ok(rx)
+    }
+
+    fn put(&self, client_id: String, user_id: String, rx: Receiver<Frame>) {
+        self.sessions().insert(
+            client_id,
+            Session {
+                user_id,
+                rx: Some(rx),
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    pub async fn remove_expired(self) {
+        let mut interval = interval(SESSION_TIMEOUT);
+
+        loop {
+            interval.tick().await;
+
+            // a poll in progress is never older than `POLL_TIMEOUT`
+            self.sessions()
+                .retain(|_, s| s.last_seen.elapsed() < SESSION_TIMEOUT);
+        }
+    }
+}
+
+/// Receiver of a session being polled, given back to `Polls` when the poll
+/// ends, also when its request is dropped before. The session is removed once
+/// `rx` is `None`, its client is closed.
+struct Lease {
+    polls: Polls,
+    client_id: String,
+    user_id: String,
+    rx: Option<Receiver<Frame>>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let client_id = std::mem::take(&mut self.client_id);
+
+        match self.rx.take() {
+            Some(rx) => self
+                .polls
+                .put(client_id, std::mem::take(&mut self.user_id), rx),
+            None => {
+                self.polls.sessions().remove(&client_id);
+            }
+        }
+    }
+}
+
+/// Creates a session when `x-pikav-client-id` is missing, then waits for
+/// events on it and returns them as a JSON batch.
+#[get("/poll")]
+async fn poll(
+    publisher: Data<Publisher<Frame>>,
+    polls: Data<Polls>,
+    policy: Data<dyn Policy>,
+    client: Option<ReqClient>,
+    JwtPayload(payload): JwtPayload<JwtClaims>,
+    LastEventId(last_event_id): LastEventId,
+) -> Result<HttpResponse, ApiError> {
+    let (rx, id) = match client {
+        Some(ReqClient(id)) => (polls.take(&id, &payload.sub)?, id),
+        None => {
+            publisher
+                .create_client(ClientOptions {
+                    send_id: true,
+                    last_event_id,
+                    user_id: Some(payload.sub.to_owned()),
+                    audiences: policy.audiences(&payload),
+                    ..Default::default()
+                })
+                .await?
+        }
+    };
+
+    let mut lease = Lease {
+        polls: polls.get_ref().clone(),
+        client_id: id.to_owned(),
+        user_id: payload.sub,
+        rx: Some(rx),
+    };
+    let rx = lease.rx.as_mut().expect("the lease holds the receiver");
+
+    let deadline = Instant::now() + POLL_TIMEOUT;
+    let mut events = Vec::new();
+    let mut closed = false;
+
+    while events.is_empty() {
+        match timeout(
+            deadline.saturating_duration_since(Instant::now()),
+            rx.recv(),
+        )
+        .await
+        {
+            Ok(Some(frame)) => events.extend(to_json(frame)),
+            Ok(None) => {
+                closed = true;
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+
+    while events.len() < MAX_BATCH {
+        match rx.try_recv() {
+            Some(frame) => events.extend(to_json(frame)),
+            None => break,
+        }
+    }
+
+    if closed {
+        lease.rx = None;
+    }
+
+    drop(lease);
+
+    Ok(HttpResponse::Ok().json(json!({ "client_id": id, "events": events })))
+}
+
+/// Heartbeats and other comment frames have no data and are skipped.
+fn to_json(frame: Frame) -> Option<Value> {
+    let text = String::from(frame);
+    let mut id = None;
+    let mut event = "message";
+    let mut data = Vec::new();
+
+    for line in text.lines() {
+        if let Some(value) = line.strip_prefix("id: ") {
+            id = value.parse::<u64>().ok();
+        } else if let Some(value) = line.strip_prefix("event: ") {
+            event = value;
+        } else if let Some(value) = line.strip_prefix("data: ") {
+            data.push(value);
+        }
+    }
+
+    if data.is_empty() {
+        return None;
+    }
+
+    let data = data.join("\n");
+
+    Some(json!({
+        "id": id,
+        "event": event,
+        "data": serde_json::from_str::<Value>(&data).unwrap_or(Value::String(data)),
+    }))
+}
@@ -0,0 +1,121 @@
+use actix_web::{
+    post,
+    web::{Data, Json},
+    HttpResponse,
+};
+use pikav::{publisher::Message, Event, Topic};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use twa_jwks::actix_web::JwtPayload;
+
+use crate::{client, error::ApiError, policy::Policy, Frame, JwtClaims, Publisher};
+
+/// Same shape as `pikav_client::Event`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PublishEvent {
+    pub user_id: String,
+    #[serde(default)]
+    pub user_ids: Vec<String>,
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    pub topic: Topic,
+    pub name: String,
+    #[serde(default)]
+    pub data: Value,
+    pub metadata: Option<Value>,
+    #[serde(default)]
+    pub retain: bool,
+    /// Only sends the event to this session.
+    pub client_id: Option<String>,
+    /// Sends the event to every session but this one.
+    pub exclude_client_id: Option<String>,
+}
+
+impl PublishEvent {
+    /// Whether the only recipient is `user_id`.
+    fn is_to(&self, user_id: &str) -> bool {
+        self.user_id == user_id
+            && user_id != "*"
+            && self.user_ids.is_empty()
+            && self.audiences.is_empty()
+            && self.client_id.is_none()
+    }
+}
+
+impl From<PublishEvent> for client::Event {
+    fn from(event: PublishEvent) -> Self {
+        client::Event {
+            user_id: event.user_id,
+            user_ids: event.user_ids,
+            audiences: event.audiences,
+            topic: event.topic.into(),
+            name: event.name,
+            data: Some(event.data.into()),
+            metadata: event.metadata.map(Into::into),
+            retain: event.retain,
+            client_id: event.client_id,
+            exclude_client_id: event.exclude_client_id,
+        }
+    }
+}
+
+#[post("/publish")]
+async fn publish(
+    publisher: Data<Publisher<Frame>>,
+    nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
+    JwtPayload(payload): JwtPayload<JwtClaims>,
+    Json(events): Json<Vec<PublishEvent>>,
+) -> Result<HttpResponse, ApiError> {
+    let broadcast = policy.can_broadcast(&payload);
+
+    if events.iter().any(|event| {
+        !policy.can_publish(event.topic.as_str(), &payload)
+            || (!broadcast && !event.is_to(&payload.sub))
+    }) {
+        return ApiError::Forbidden.into_response();
+    }
+
+    publish_events(&publisher, &nodes, events).await;
+
+    Ok(HttpResponse::Ok().json(json! ({ "success": true })))
+}
+
+/// Publishes on this node and forwards to every other node, like the
+/// `PublishEvents` rpc does with `propagate`.
+pub(crate) async fn publish_events(
+    publisher: &Publisher<Frame>,
+    nodes: &[client::Client],
+    events: Vec<PublishEvent>,
+) {
+    if events.is_empty() {
+        return;
+    }
+
+    let messages = events
+        .iter()
+        .map(|event| Message {
+            event: Event::<Value, Value> {
+                topic: event.topic.to_string(),
+                name: event.name.to_owned(),
+                data: event.data.clone(),
+                metadata: event.metadata.clone(),
+                filters: None,
+            },
+            user_id: event.user_id.to_owned(),
+            user_ids: event.user_ids.to_owned(),
+            audiences: event.audiences.to_owned(),
+            retain: event.retain,
+            client_id: event.client_id.to_owned(),
+            exclude_client_id: event.exclude_client_id.to_owned(),
+        })
+        .collect::<Vec<_>>();
+
+    publisher.publish_events(messages).await;
+
+    let events = events.into_iter().map(Into::into).collect::<Vec<_>>();
+
+    for node in nodes.iter() {
+        node.publish_events(events.clone());
+    }
+}
@@ -0,0 +1,195 @@
+use actix_web::{
+    post,
+    web::{self, Bytes, Data},
+    HttpRequest, HttpResponse,
+};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    client,
+    error::ApiError,
+    publish::{publish_events, PublishEvent},
+    Frame, Publisher,
+};
+
+/// A webhook source posting to `/webhooks/{source}`.
+///
+/// The request is signed with `hex(hmac_sha256(secret, "{timestamp}.{body}"))`,
+/// an optional `sha256=` prefix is accepted. `topic`, `name` and `user_id` are
+/// templates where `{a.b}` is replaced by that field of the JSON payload.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppWebhook {
+    pub source: String,
+    pub secret: String,
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    #[serde(default = "default_timestamp_header")]
+    pub timestamp_header: String,
+    /// Maximum age of the timestamp in seconds.
+    #[serde(default = "default_tolerance")]
+    pub tolerance: u64,
+    pub topic: String,
+    pub name: String,
+    #[serde(default = "default_user_id")]
+    pub user_id: String,
+}
+
+fn default_signature_header() -> String {
+    "x-pikav-signature".to_owned()
+}
+
+fn default_timestamp_header() -> String {
+    "x-pikav-timestamp".to_owned()
+}
+
+fn default_tolerance() -> u64 {
+    300
+}
+
+fn default_user_id() -> String {
+    "*".to_owned()
+}
+
+pub struct Webhooks {
+    sources: HashMap<String, AppWebhook>,
+    /// Signatures accepted within their tolerance, a second delivery is a replay.
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl Webhooks {
+    pub fn new(webhooks: Vec<AppWebhook>) -> Self {
+        Self {
+            sources: webhooks
+                .into_iter()
+                .map(|config| (config.source.to_owned(), config))
+                .collect(),
+            seen: Mutex::default(),
+        }
+    }
+
+    fn verify(&self, config: &AppWebhook, req: &HttpRequest, body: &[u8]) -> Result<(), ApiError> {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| ApiError::BadRequest(format!("{name} is missing from header")))
+        };
+
+        let timestamp = header(&config.timestamp_header)?
+            .parse::<u64>()
+            .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+        let signature = header(&config.signature_header)?;
+        let signature = hex::decode(signature.strip_prefix("sha256=").unwrap_or(signature))
+            .map_err(|_| ApiError::Unauthorized)?;
+
+        let now = now();
+
+        if now.abs_diff(timestamp) > config.tolerance {
+            return Err(ApiError::Unauthorized);
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(config.secret.as_bytes())
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        mac.verify_slice(&signature)
+            .map_err(|_| ApiError::Unauthorized)?;
+
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        seen.retain(|_, expires_at| *expires_at >= now);
+
+        if seen
+            .insert(signature, timestamp + config.tolerance)
+            .is_some()
+        {
+            return Err(ApiError::Unauthorized);
+        }
+
+        Ok(())
+    }
+}
+
+#[post(r"/webhooks/{source}")]
+async fn webhook(
+    req: HttpRequest,
+    params: web::Path<(String,)>,
+    body: Bytes,
+    webhooks: Data<Webhooks>,
+    publisher: Data<Publisher<Frame>>,
+    nodes: Data<Vec<client::Client>>,
+) -> Result<HttpResponse, ApiError> {
+    let Some(config) = webhooks.sources.get(&params.into_inner().0) else {
+        return ApiError::NotFound.into_response();
+    };
+
+    webhooks.verify(config, &req, &body)?;
+
+    let data =
+        serde_json::from_slice::<Value>(&body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+    let render = |template: &str| {
+        render(template, &data)
+            .ok_or_else(|| ApiError::BadRequest(format!("payload does not match {template}")))
+    };
+
+    let event = PublishEvent {
+        user_id: render(&config.user_id)?,
+        user_ids: Vec::new(),
+        audiences: Vec::new(),
+        topic: render(&config.topic)?.parse()?,
+        name: render(&config.name)?,
+        metadata: Some(json!({ "source": config.source })),
+        retain: false,
+        client_id: None,
+        exclude_client_id: None,
+        data,
+    };
+
+    publish_events(&publisher, &nodes, vec![event]).await;
+
+    Ok(HttpResponse::Ok().json(json! ({ "success": true })))
+}
+
+/// Replaces each `{a.b}` of `template` by that string or number field of `data`.
+fn render(template: &str, data: &Value) -> Option<String> {
+    let mut value = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let len = rest[start..].find('}')?;
+        let field = rest[start + 1..start + len]
+            .split('.')
+            .try_fold(data, |data, key| data.get(key))?;
+
+        value.push_str(&rest[..start]);
+
+        match field {
+            Value::String(field) => value.push_str(field),
+            Value::Number(field) => value.push_str(&field.to_string()),
+            _ => return None,
+        }
+
+        rest = &rest[start + len + 1..];
+    }
+
+    value.push_str(rest);
+
+    Some(value)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
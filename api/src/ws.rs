@@ -0,0 +1,136 @@
+use actix_web::{
+    get,
+    rt::spawn,
+    web::{Data, Payload},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use actix_ws::{Message, Session};
+use pikav::TopicFilter;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    client,
+    error::ApiError,
+    extractor::{LastEventId, TokenPayload},
+    policy::Policy,
+    subscribe_client, unsubscribe_client, ClientOptions, Frame, JwtClaims, Publisher,
+};
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Command {
+    Subscribe {
+        filter: TopicFilter,
+        predicate: Option<String>,
+    },
+    Unsubscribe {
+        filter: TopicFilter,
+    },
+}
+
+/// Carries the same frames as `/events` as text messages, the socket accepts
+/// `{"type": "subscribe", "filter": "...", "predicate": "..."}` and
+/// `unsubscribe` commands. Browsers pass the token as the `access_token`
+/// query parameter.
+#[get("/ws")]
+async fn ws(
+    req: HttpRequest,
+    body: Payload,
+    publisher: Data<Publisher<Frame>>,
+    nodes: Data<Vec<client::Client>>,
+    policy: Data<dyn Policy>,
+    TokenPayload(payload): TokenPayload<JwtClaims>,
+    LastEventId(last_event_id): LastEventId,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, session, mut stream) = actix_ws::handle(&req, body)?;
+
+    let (mut rx, id) = publisher
+        .create_client(ClientOptions {
+            send_id: true,
+            last_event_id,
+            user_id: Some(payload.sub.to_owned()),
+            audiences: policy.audiences(&payload),
+            ..Default::default()
+        })
+        .await
+        .map_err(ApiError::from)?;
+
+    spawn({
+        let mut session = session.clone();
+
+        async move {
+            while let Some(frame) = rx.recv().await {
+                if session.text(String::from(frame)).await.is_err() {
+                    return;
+                }
+            }
+
+            let _ = session.close(None).await;
+        }
+    });
+
+    spawn(async move {
+        let mut session = session;
+
+        while let Some(Ok(msg)) = stream.recv().await {
+            let text = match msg {
+                Message::Text(text) => text,
+                Message::Ping(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+
+                    continue;
+                }
+                Message::Close(_) => break,
+                _ => continue,
+            };
+
+            let res = match serde_json::from_str::<Command>(&text) {
+                Ok(Command::Subscribe { filter, predicate }) => {
+                    match policy.can_subscribe(filter.as_str(), &payload) {
+                        true => {
+                            subscribe_client(
+                                &publisher,
+                                &nodes,
+                                &filter,
+                                predicate.as_deref(),
+                                &policy.audiences(&payload),
+                                &payload.sub,
+                                &id,
+                            )
+                            .await
+                        }
+                        false => Err(ApiError::Forbidden),
+                    }
+                }
+                Ok(Command::Unsubscribe { filter }) => {
+                    unsubscribe_client(&publisher, &nodes, &filter, &payload.sub, &id).await
+                }
+                Err(e) => Err(ApiError::BadRequest(e.to_string())),
+            };
+
+            if let Err(e) = res {
+                if send_error(&mut session, &e).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+async fn send_error(session: &mut Session, e: &ApiError) -> Result<(), actix_ws::Closed> {
+    let data = json!({"code": e.status_code().as_u16(), "message": e.to_string()});
+
+    session
+        .text(String::from(Frame::without_id(
+            "error",
+            data.to_string().into(),
+        )))
+        .await
+}
@@ -1,23 +1,76 @@
-use bytes::Bytes;
+use metrics::histogram;
 use pikav::{
-    publisher::{Message, Publisher},
-    Event, SimpleEvent,
+    publisher::{is_presence, Error, Frame, Message, Predicate, Publisher, SessionInfo},
+    Event, SimpleEvent, TopicFilter,
 };
 use pikav_client::{
     timada::{
         pikav_server::{self, PikavServer},
-        PublishEventsReply, PublishEventsRequest, PublishReply, PublishRequest, SubscribeReply,
+        DisconnectReply, DisconnectRequest, DisconnectUserReply, DisconnectUserRequest,
+        ListSessionsReply, ListSessionsRequest, PresenceReply, PresenceRequest, PublishEventsReply,
+        PublishEventsRequest, PublishReply, PublishRequest, Session, SubscribeReply,
         SubscribeRequest, UnsubscribeReply, UnsubscribeRequest,
     },
-    Client,
+    Client, SECRET_METADATA,
 };
 use serde_json::Value;
+use std::time::Instant;
+use tokio::sync::broadcast::error::RecvError;
 use tonic::{transport::Server, Request, Response, Status};
+use tracing::error;
+
+/// Records the duration of a request when dropped, whatever its result.
+struct RequestTimer {
+    method: &'static str,
+    started_at: Instant,
+}
+
+impl RequestTimer {
+    fn new(method: &'static str) -> Self {
+        Self {
+            method,
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        histogram!("pikav_grpc_request_duration_seconds", "method" => self.method)
+            .record(self.started_at.elapsed().as_secs_f64());
+    }
+}
 
 #[derive(Default)]
 pub struct Pikav {
-    pub publisher: Publisher<Bytes>,
+    pub publisher: Publisher<Frame>,
     pub nodes: Vec<Client>,
+    pub secret: Option<String>,
+}
+
+impl Pikav {
+    /// Listing and disconnecting sessions or reading presence needs the secret
+    /// of the cluster, without one the cluster port must only be reachable by
+    /// the nodes.
+    fn is_authorized<M>(&self, request: &Request<M>) -> bool {
+        let Some(secret) = &self.secret else {
+            return true;
+        };
+
+        let given = request
+            .metadata()
+            .get(SECRET_METADATA)
+            .map(|value| value.as_bytes())
+            .unwrap_or_default();
+
+        // compared in constant time
+        given.len() == secret.len()
+            && given
+                .iter()
+                .zip(secret.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
 }
 
 #[tonic::async_trait]
@@ -26,6 +79,7 @@ impl pikav_server::Pikav for Pikav {
         &self,
         request: Request<PublishRequest>,
     ) -> Result<Response<PublishReply>, Status> {
+        let _timer = RequestTimer::new("publish");
         let req = request.into_inner();
 
         // let mut messages: Vec<Message<SimpleEvent>> = Vec::new();
@@ -53,6 +107,11 @@ impl pikav_server::Pikav for Pikav {
                     data: event.data.to_owned(),
                 },
                 user_id: event.user_id.to_owned(),
+                user_ids: event.user_ids.to_owned(),
+                audiences: event.audiences.to_owned(),
+                retain: event.retain,
+                client_id: event.client_id.to_owned(),
+                exclude_client_id: event.exclude_client_id.to_owned(),
             })
             .collect::<_>();
 
@@ -71,9 +130,10 @@ impl pikav_server::Pikav for Pikav {
         &self,
         request: Request<PublishEventsRequest>,
     ) -> Result<Response<PublishEventsReply>, Status> {
+        let _timer = RequestTimer::new("publish_events");
         let req = request.into_inner();
 
-        let messages = req
+        let (presence, messages): (Vec<_>, Vec<_>) = req
             .events
             .iter()
             .map(|event| Message {
@@ -85,8 +145,18 @@ impl pikav_server::Pikav for Pikav {
                     filters: None,
                 },
                 user_id: event.user_id.to_owned(),
+                user_ids: event.user_ids.to_owned(),
+                audiences: event.audiences.to_owned(),
+                retain: event.retain,
+                client_id: event.client_id.to_owned(),
+                exclude_client_id: event.exclude_client_id.to_owned(),
             })
-            .collect::<_>();
+            .partition(|message| is_presence(&message.event.topic));
+
+        // forwarded by the node of the session, see `forward_presence`
+        self.publisher
+            .publish_presence(presence.into_iter().map(|message| message.event).collect())
+            .await;
 
         self.publisher.publish_events(messages).await;
 
@@ -103,12 +173,34 @@ impl pikav_server::Pikav for Pikav {
         &self,
         request: Request<SubscribeRequest>,
     ) -> Result<Response<SubscribeReply>, Status> {
+        let _timer = RequestTimer::new("subscribe");
         let req = request.into_inner();
 
+        let filter = req
+            .filter
+            .parse::<TopicFilter>()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let predicate = match req.predicate.as_str() {
+            "" => None,
+            predicate => Some(
+                predicate
+                    .parse::<Predicate>()
+                    .map_err(|e| Status::invalid_argument(e.to_string()))?,
+            ),
+        };
+
         self.publisher
-            .subscribe(req.filter, req.user_id, req.client_id)
+            .subscribe_with_predicate(filter, predicate, &req.user_id, &req.client_id)
             .await
-            .ok();
+            .or_else(ignore_session_not_found)
+            .map_err(to_status)?;
+
+        self.publisher
+            .set_audiences(req.audiences, req.user_id, req.client_id)
+            .await
+            .or_else(ignore_session_not_found)
+            .map_err(to_status)?;
 
         Ok(Response::new(SubscribeReply { success: true }))
     }
@@ -117,21 +209,174 @@ impl pikav_server::Pikav for Pikav {
         &self,
         request: Request<UnsubscribeRequest>,
     ) -> Result<Response<UnsubscribeReply>, Status> {
+        let _timer = RequestTimer::new("unsubscribe");
         let req = request.into_inner();
 
+        let filter = req
+            .filter
+            .parse::<TopicFilter>()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
         self.publisher
-            .unsubscribe(req.filter, req.user_id, req.client_id)
+            .unsubscribe(filter, req.user_id, req.client_id)
             .await
-            .ok();
+            .or_else(ignore_session_not_found)
+            .map_err(to_status)?;
 
         Ok(Response::new(UnsubscribeReply { success: true }))
     }
+
+    async fn list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsReply>, Status> {
+        let _timer = RequestTimer::new("list_sessions");
+
+        if !self.is_authorized(&request) {
+            return Err(Status::unauthenticated("invalid cluster secret"));
+        }
+
+        let req = request.into_inner();
+
+        let sessions = match req.user_id {
+            Some(user_id) => self.publisher.user_sessions(&user_id).await,
+            None => self.publisher.sessions().await,
+        };
+
+        Ok(Response::new(ListSessionsReply {
+            sessions: sessions.into_iter().map(to_session).collect(),
+        }))
+    }
+
+    async fn disconnect(
+        &self,
+        request: Request<DisconnectRequest>,
+    ) -> Result<Response<DisconnectReply>, Status> {
+        let _timer = RequestTimer::new("disconnect");
+
+        if !self.is_authorized(&request) {
+            return Err(Status::unauthenticated("invalid cluster secret"));
+        }
+
+        let req = request.into_inner();
+
+        let success = self.publisher.disconnect(&req.client_id).await.is_ok();
+
+        Ok(Response::new(DisconnectReply { success }))
+    }
+
+    async fn disconnect_user(
+        &self,
+        request: Request<DisconnectUserRequest>,
+    ) -> Result<Response<DisconnectUserReply>, Status> {
+        let _timer = RequestTimer::new("disconnect_user");
+
+        if !self.is_authorized(&request) {
+            return Err(Status::unauthenticated("invalid cluster secret"));
+        }
+
+        let req = request.into_inner();
+
+        let disconnected = self.publisher.disconnect_user(&req.user_id).await;
+
+        Ok(Response::new(DisconnectUserReply {
+            disconnected: disconnected as u64,
+        }))
+    }
+
+    async fn presence(
+        &self,
+        request: Request<PresenceRequest>,
+    ) -> Result<Response<PresenceReply>, Status> {
+        let _timer = RequestTimer::new("presence");
+
+        if !self.is_authorized(&request) {
+            return Err(Status::unauthenticated("invalid cluster secret"));
+        }
+
+        let req = request.into_inner();
+
+        let users = self.publisher.presence(&req.topic).await;
+
+        Ok(Response::new(PresenceReply { users }))
+    }
+}
+
+/// Sends the presence events of this node's sessions to the other nodes.
+async fn forward_presence(publisher: Publisher<Frame>, nodes: Vec<Client>) {
+    let mut events = publisher.watch_presence();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                error!("{skipped} presence events were not forwarded to the other nodes");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let event = pikav_client::Event {
+            user_id: "*".to_owned(),
+            topic: event.topic,
+            name: event.name,
+            data: Some(event.data.into()),
+            metadata: event.metadata.map(Into::into),
+            ..Default::default()
+        };
+
+        for node in nodes.iter() {
+            node.publish_events(vec![event.clone()]);
+        }
+    }
+}
+
+fn to_session(session: SessionInfo) -> Session {
+    Session {
+        id: session.id,
+        user_id: session.user_id,
+        filters: session.filters,
+        created_at: session.created_at,
+        queue_len: session.queue_len as u64,
+    }
+}
+
+/// Sessions live on a single node, not finding one here is expected.
+fn ignore_session_not_found(e: Error) -> Result<(), Error> {
+    match e {
+        Error::SessionNotFound => Ok(()),
+        e => Err(e),
+    }
+}
+
+fn to_status(e: Error) -> Status {
+    match e {
+        Error::SessionNotFound => Status::not_found("session not found"),
+        Error::UserMismatch => Status::permission_denied("session belongs to another user"),
+        Error::Full => Status::internal("session buffer is full"),
+        Error::TooManySessions(max) => {
+            Status::resource_exhausted(format!("at most {max} sessions per user"))
+        }
+        Error::TooManyFilters(max) => {
+            Status::resource_exhausted(format!("at most {max} filters per session"))
+        }
+        Error::TooManySubscribes(max) => {
+            Status::resource_exhausted(format!("at most {max} subscribes per minute"))
+        }
+        Error::Draining => Status::unavailable("node is draining"),
+        Error::Syntax(syntax) => {
+            Status::invalid_argument(format!("only {syntax} filters are accepted"))
+        }
+    }
 }
 
 pub struct ClusterOptions {
     pub addr: String,
-    pub publisher: Publisher<Bytes>,
+    pub publisher: Publisher<Frame>,
     pub nodes: Vec<Client>,
+    /// Required by the rpcs listing and disconnecting sessions and reading
+    /// presence, see `Pikav::is_authorized`.
+    pub secret: Option<String>,
 }
 
 pub struct Cluster {
@@ -149,8 +394,16 @@ impl Cluster {
         let pikav = Pikav {
             publisher: self.options.publisher.clone(),
             nodes: self.options.nodes.clone(),
+            secret: self.options.secret.clone(),
         };
 
+        if !self.options.nodes.is_empty() {
+            tokio::spawn(forward_presence(
+                self.options.publisher.clone(),
+                self.options.nodes.clone(),
+            ));
+        }
+
         println!("PikavServer listening on {addr}");
 
         Server::builder()
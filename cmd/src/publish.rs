@@ -34,6 +34,8 @@ impl Publish {
 
         client.publish_events(vec![Event {
             user_id: "hubert@clients".to_owned(),
+            user_ids: Vec::new(),
+            audiences: Vec::new(),
             topic: "todos/1".to_owned(),
             name: "Created".to_owned(),
             data: Some(
@@ -45,6 +47,9 @@ impl Publish {
                 .into(),
             ),
             metadata: None,
+            retain: false,
+            client_id: None,
+            exclude_client_id: None,
         }]);
 
         actix_rt::time::sleep(Duration::from_secs(1)).await;
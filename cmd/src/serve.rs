@@ -1,15 +1,80 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc, time::Duration};
 
+use actix_rt::signal::ctrl_c;
+#[cfg(unix)]
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_web::{web, HttpResponse, HttpServer};
 use config::{Config, ConfigError, Environment, File};
-use pikav_api::{client::Client, App, AppCors, AppJwks, AppOptions, Publisher};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+#[cfg(feature = "sqlite")]
+use pikav::history::SqliteHistory;
+use pikav::{
+    history::{History, MemoryHistory, Retention},
+    publisher::{DrainOptions, Overflow, PublisherOptions, Quotas},
+    FilterSyntax,
+};
+use pikav_api::{
+    client::Client, policy::Policy, App, AppAcl, AppCors, AppJwks, AppOptions, AppWebhook,
+    Publisher,
+};
 use pikav_cluster::{Cluster, ClusterOptions};
 use serde::Deserialize;
-use tracing::Level;
+use tracing::{error, Level};
 
 #[derive(Debug, Deserialize)]
 pub struct ServeAddr {
     pub api: String,
     pub cluster: String,
+    /// Serves Prometheus metrics on `/metrics` when set.
+    pub metrics: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ServeHistoryStorage {
+    #[default]
+    Memory,
+    /// Needs the `sqlite` feature.
+    Sqlite,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ServeHistory {
+    #[serde(default)]
+    pub storage: ServeHistoryStorage,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub retention: Retention,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ServeChannel {
+    pub buffer_size: Option<usize>,
+    #[serde(default)]
+    pub overflow: Overflow,
+}
+
+/// Applied on SIGTERM, before exiting.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ServeDrain {
+    /// Milliseconds before a client reconnects.
+    pub retry: u64,
+    /// Maximum milliseconds randomly added to `retry`.
+    pub jitter: u64,
+    /// Seconds given to clients to read their events, then to peers to
+    /// receive the queued events.
+    pub timeout: u64,
+}
+
+impl Default for ServeDrain {
+    fn default() -> Self {
+        Self {
+            retry: 3000,
+            jitter: 5000,
+            timeout: 10,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -17,8 +82,25 @@ pub struct Serve {
     pub addr: ServeAddr,
     pub cors: Option<AppCors>,
     pub jwks: Option<AppJwks>,
+    pub acl: Option<AppAcl>,
+    pub webhooks: Option<Vec<AppWebhook>>,
     pub nodes: Vec<String>,
+    /// Shared by the nodes, required to list and disconnect sessions through
+    /// the cluster port. That port must stay private when it is not set.
+    pub cluster_secret: Option<String>,
     pub log: Option<String>,
+    pub history: Option<ServeHistory>,
+    pub channel: Option<ServeChannel>,
+    /// Seconds between two heartbeats.
+    pub heartbeat_interval: Option<u64>,
+    /// Seconds a session whose connection closed can be resumed, 0 disables it.
+    pub resume_grace: Option<u64>,
+    /// Only accepts `glob` or `mqtt` filters, both when unset.
+    pub filter_syntax: Option<FilterSyntax>,
+    #[serde(default)]
+    pub quotas: Quotas,
+    #[serde(default)]
+    pub drain: ServeDrain,
 }
 
 impl Serve {
@@ -44,29 +126,157 @@ impl Serve {
         tracing::subscriber::set_global_default(subscriber)
             .expect("setting default subscriber failed");
 
+        if let Some(addr) = &self.addr.metrics {
+            // installed first, metrics recorded before are lost
+            let handle = PrometheusBuilder::new()
+                .add_global_label("node", self.addr.cluster.to_owned())
+                .install_recorder()
+                .expect("failed to install metrics recorder");
+
+            let server = serve_metrics(addr, handle)?;
+
+            println!("Pikav metrics listening on {addr}");
+
+            actix_rt::spawn(server);
+        }
+
         let nodes = match Client::from_vec(self.nodes.clone()) {
-            Ok(nodes) => nodes,
+            Ok(nodes) => nodes
+                .into_iter()
+                .map(|node| node.secret(self.cluster_secret.clone()))
+                .collect::<Vec<_>>(),
             Err(e) => panic!("{e:?}"),
         };
 
-        let publisher = Publisher::start();
+        let history: Arc<dyn History> = match &self.history {
+            #[cfg(feature = "sqlite")]
+            Some(ServeHistory {
+                storage: ServeHistoryStorage::Sqlite,
+                path,
+                retention,
+            }) => {
+                let path = path.as_deref().unwrap_or("pikav.db");
+
+                match SqliteHistory::open(path, retention.clone()) {
+                    Ok(history) => Arc::new(history),
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+            #[cfg(not(feature = "sqlite"))]
+            Some(ServeHistory {
+                storage: ServeHistoryStorage::Sqlite,
+                path,
+                ..
+            }) => panic!("sqlite history {path:?} needs pikav built with the sqlite feature"),
+            Some(ServeHistory { retention, .. }) => Arc::new(MemoryHistory::new(retention.clone())),
+            None => Arc::new(MemoryHistory::default()),
+        };
+
+        let mut options = PublisherOptions {
+            history,
+            quotas: self.quotas,
+            filter_syntax: self.filter_syntax,
+            ..Default::default()
+        };
+
+        if let Some(channel) = &self.channel {
+            options.buffer_size = channel.buffer_size.unwrap_or(options.buffer_size);
+            options.overflow = channel.overflow;
+        }
+
+        if let Some(secs) = self.heartbeat_interval {
+            options.heartbeat_interval = Duration::from_secs(secs.max(1));
+        }
+
+        if let Some(secs) = self.resume_grace {
+            options.resume_grace = Duration::from_secs(secs);
+        }
+
+        let publisher = Publisher::start_with_options(options);
 
         let cluster = Cluster::new(ClusterOptions {
             addr: self.addr.cluster.to_owned(),
             publisher: publisher.clone(),
             nodes: nodes.clone(),
+            secret: self.cluster_secret.clone(),
         });
 
         let app = App::new(AppOptions {
             listen: self.addr.api.to_owned(),
             jwks: self.jwks.clone(),
             cors: self.cors.clone(),
-            publisher,
-            nodes,
+            publisher: publisher.clone(),
+            nodes: nodes.clone(),
+            policy: self.acl.clone().map(|acl| Arc::new(acl) as Arc<dyn Policy>),
+            webhooks: self.webhooks.clone().unwrap_or_default(),
         });
 
         actix_rt::spawn(async move { cluster.serve().await });
 
-        app.run().await
+        let drain = DrainOptions {
+            retry: Duration::from_millis(self.drain.retry),
+            jitter: Duration::from_millis(self.drain.jitter),
+            timeout: Duration::from_secs(self.drain.timeout),
+        };
+
+        app.run_until(async move {
+            shutdown_signal().await;
+
+            println!("Pikav draining sessions");
+
+            let timeout = drain.timeout;
+            publisher.drain(drain).await;
+
+            for node in nodes.iter() {
+                if !node.flush(timeout).await {
+                    error!("events queued to a node were not sent before shutdown");
+                }
+            }
+        })
+        .await
     }
 }
+
+/// SIGTERM, or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen to SIGTERM");
+
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = ctrl_c() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c().await;
+    }
+}
+
+fn serve_metrics(
+    addr: &str,
+    handle: PrometheusHandle,
+) -> Result<actix_web::dev::Server, std::io::Error> {
+    Ok(HttpServer::new(move || {
+        let handle = handle.clone();
+
+        actix_web::App::new().route(
+            "/metrics",
+            web::get().to(move || {
+                let body = handle.render();
+
+                async move {
+                    HttpResponse::Ok()
+                        .content_type("text/plain; version=0.0.4")
+                        .body(body)
+                }
+            }),
+        )
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(addr)?
+    .run())
+}
@@ -122,6 +122,8 @@ async fn create_todo(user_id: String, text: String) -> Result<(), ServerFnError>
 
         client.publish_events(vec![Event {
             user_id,
+            user_ids: Vec::new(),
+            audiences: Vec::new(),
             topic: format!("todos/{id}"),
             name: "Created".to_owned(),
             data: Some(
@@ -134,6 +136,9 @@ async fn create_todo(user_id: String, text: String) -> Result<(), ServerFnError>
                 .into(),
             ),
             metadata: None,
+            retain: false,
+            client_id: None,
+            exclude_client_id: None,
         }]);
     });
 
@@ -170,6 +175,8 @@ async fn delete_todo(user_id: String, id: i64) -> Result<(), ServerFnError> {
 
         client.publish_events(vec![Event {
             user_id,
+            user_ids: Vec::new(),
+            audiences: Vec::new(),
             topic: format!("todos/{id}"),
             name: "Deleted".to_owned(),
             data: Some(
@@ -179,6 +186,9 @@ async fn delete_todo(user_id: String, id: i64) -> Result<(), ServerFnError> {
                 .into(),
             ),
             metadata: None,
+            retain: false,
+            client_id: None,
+            exclude_client_id: None,
         }]);
     });
 
@@ -1,19 +1,27 @@
 use error::ClientError;
+use metrics::gauge;
 use parking_lot::RwLock;
 use serde::Deserialize;
 use serde_json::Map;
 use std::{collections::HashMap, sync::Arc, time::Duration};
 use timada::{
-    pikav_client::PikavClient, PublishEventsRequest, PublishRequest, SimpleEvent, Struct,
-    SubscribeReply, UnsubscribeReply,
+    pikav_client::PikavClient, DisconnectReply, DisconnectUserReply, ListSessionsReply,
+    PresenceReply, PublishEventsRequest, PublishRequest, SimpleEvent, Struct, SubscribeReply,
+    UnsubscribeReply,
 };
 use tokio::time::{interval_at, sleep, Instant};
 use tonic::transport::Channel;
 use tracing::error;
 use url::Url;
 
-pub use timada::{value::Kind, Event, ListValue, SubscribeRequest, UnsubscribeRequest, Value};
-pub use tonic::Status;
+pub use timada::{
+    value::Kind, DisconnectRequest, DisconnectUserRequest, Event, ListSessionsRequest, ListValue,
+    PresenceRequest, Session, SubscribeRequest, UnsubscribeRequest, Value,
+};
+pub use tonic::{Code, Status};
+
+/// Request metadata holding the secret shared by the nodes of a cluster.
+pub const SECRET_METADATA: &str = "x-pikav-secret";
 
 mod error;
 
@@ -147,9 +155,11 @@ pub struct ClientInstanceOptions {
 #[derive(Clone)]
 pub struct Client {
     channel: Channel,
+    url: String,
     queue: Arc<RwLock<Vec<SimpleEvent>>>,
     queue_events: Arc<RwLock<Vec<Event>>>,
     namespace: Option<String>,
+    secret: Option<String>,
     pub same_region: bool,
 }
 
@@ -199,9 +209,11 @@ impl Client {
 
         let client = Self {
             channel,
+            url: options.url,
             queue: Arc::new(RwLock::new(Vec::new())),
             queue_events: Arc::new(RwLock::new(Vec::new())),
             namespace: options.namespace,
+            secret: None,
             same_region,
         };
 
@@ -261,6 +273,7 @@ impl Client {
                 {
                     let mut queue = me.queue.write();
                     queue.drain(0..event_size);
+                    me.record_queue_length("publish", queue.len());
                 }
             }
         });
@@ -316,6 +329,7 @@ impl Client {
                 {
                     let mut queue = me.queue_events.write();
                     queue.drain(0..event_size);
+                    me.record_queue_length("publish_events", queue.len());
                 }
             }
         });
@@ -324,11 +338,55 @@ impl Client {
     pub fn publish(&self, events: Vec<SimpleEvent>) {
         let mut queue = self.queue.write();
         queue.extend(events);
+        self.record_queue_length("publish", queue.len());
     }
 
     pub fn publish_events(&self, events: Vec<Event>) {
         let mut queue_events = self.queue_events.write();
         queue_events.extend(events);
+        self.record_queue_length("publish_events", queue_events.len());
+    }
+
+    /// Waits for the queued events to be sent, false when some are still
+    /// queued after `timeout`.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if self.queue.read().is_empty() && self.queue_events.read().is_empty() {
+                return true;
+            }
+
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Sent with the requests listing or disconnecting sessions, the node
+    /// rejects them when its secret differs.
+    pub fn secret(mut self, value: Option<String>) -> Self {
+        self.secret = value;
+
+        self
+    }
+
+    fn request<M>(&self, message: M) -> tonic::Request<M> {
+        let mut request = tonic::Request::new(message);
+
+        if let Some(secret) = self.secret.as_deref().and_then(|s| s.parse().ok()) {
+            request.metadata_mut().insert(SECRET_METADATA, secret);
+        }
+
+        request
+    }
+
+    /// Events waiting to be sent to the node.
+    fn record_queue_length(&self, queue: &'static str, len: usize) {
+        gauge!("pikav_client_queue_length", "node" => self.url.to_owned(), "queue" => queue)
+            .set(len as f64);
     }
 
     pub async fn subscribe(
@@ -352,4 +410,48 @@ impl Client {
 
         client.unsubscribe(request).await
     }
+
+    pub async fn list_sessions(
+        &self,
+        message: ListSessionsRequest,
+    ) -> Result<tonic::Response<ListSessionsReply>, Status> {
+        let mut client = PikavClient::new(self.channel.clone());
+
+        let request = self.request(message);
+
+        client.list_sessions(request).await
+    }
+
+    pub async fn disconnect(
+        &self,
+        message: DisconnectRequest,
+    ) -> Result<tonic::Response<DisconnectReply>, Status> {
+        let mut client = PikavClient::new(self.channel.clone());
+
+        let request = self.request(message);
+
+        client.disconnect(request).await
+    }
+
+    pub async fn disconnect_user(
+        &self,
+        message: DisconnectUserRequest,
+    ) -> Result<tonic::Response<DisconnectUserReply>, Status> {
+        let mut client = PikavClient::new(self.channel.clone());
+
+        let request = self.request(message);
+
+        client.disconnect_user(request).await
+    }
+
+    pub async fn presence(
+        &self,
+        message: PresenceRequest,
+    ) -> Result<tonic::Response<PresenceReply>, Status> {
+        let mut client = PikavClient::new(self.channel.clone());
+
+        let request = self.request(message);
+
+        client.presence(request).await
+    }
 }
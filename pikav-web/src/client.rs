@@ -17,7 +17,9 @@ cfg_if! {
             eventsource::futures::EventSource,
             http::{Request, Response},
         };
+        use gloo_timers::future::TimeoutFuture;
         use log::error;
+        use pikav::TopicFilter;
         use wasm_bindgen_futures::spawn_local;
 
         type HeadersFut = Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<Headers>>>>>;
@@ -75,17 +77,67 @@ impl Client {
     pub fn run(self) -> Result<Self> {
         cfg_if! {
             if #[cfg(feature = "hydrate")] {
-                let mut source = gloo_net::eventsource::futures::EventSource::new(&self.source_url)?;
-                let mut stream = source.subscribe("message")?;
+                let source = EventSource::new(&self.source_url)?;
                 *self.source.borrow_mut() = Some(source);
-                let id = self.id.clone();
-                let listeners = self.listeners.clone();
+
+                spawn_local(self.clone().listen());
+            }
+        }
+
+        Ok(self)
+    }
+
+    pub fn endpoint(mut self, v: impl Into<String>) -> Self {
+        self.endpoint = v.into();
+
+        self
+    }
+
+    pub fn namespace(mut self, v: impl Into<String>) -> Self {
+        self.namespace = v.into();
+
+        self
+    }
+
+    pub fn close(&self) {
+        cfg_if! {
+            if #[cfg(feature = "hydrate")] {
+                if let Some(source) = self.source.borrow_mut().take() {
+                    source.close();
+                }
+            }
+        }
+    }
+
+    cfg_if! {
+        if #[cfg(feature = "hydrate")] {
+            /// Dispatches the events to the listeners. Once the connection
+            /// fails, opens a new one resuming the session so that its filters
+            /// stay subscribed.
+            async fn listen(self) {
                 let fetcher = Fetcher::from(&self);
+                let mut retry = 3000;
+                let mut resume_token = None::<String>;
+                let mut last_event_id = None::<String>;
+
+                loop {
+                    let Some(mut source) = self.source.borrow().clone() else {
+                        return;
+                    };
+
+                    let mut stream = match source.subscribe("message") {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("{e}");
+                            return;
+                        }
+                    };
 
-                spawn_local(async move {
                     while let Some(Ok((_, msg))) = stream.next().await {
-                        if msg.data().as_string() == Some("ping".to_owned()) {
-                            continue;
+                        let id = msg.last_event_id();
+
+                        if !id.is_empty() {
+                            last_event_id = Some(id);
                         }
 
                         let data = match msg.data().as_string() {
@@ -104,40 +156,62 @@ impl Client {
                             }
                         };
 
-                        if matches!(
-                            (event.topic.as_ref(), event.name.as_ref()),
-                            ("$SYS/session", "Created")
-                        ) {
-                            *id.borrow_mut() = event.data.as_str().map(|v| v.to_owned());
-
-                            let mut subscribed = HashSet::new();
-
-                            if let Some(client_id) = event.data.as_str() {
-                                let filters = {
-                                    listeners
-                                        .borrow()
-                                        .iter()
-                                        .map(|(_, f, _)| f.to_owned())
-                                        .collect::<Vec<_>>()
-                                };
-
-                                for filter in filters {
-                                    if subscribed.contains(&filter) {
-                                        continue;
+                        match (event.topic.as_ref(), event.name.as_ref()) {
+                            ("$SYS/session", "Created" | "Resumed") => {
+                                *self.id.borrow_mut() = event.data.as_str().map(|v| v.to_owned());
+
+                                resume_token = event
+                                    .metadata
+                                    .as_ref()
+                                    .and_then(|m| m.get("resume_token"))
+                                    .and_then(Value::as_str)
+                                    .map(|v| v.to_owned());
+
+                                if let Some(client_id) = event.data.as_str() {
+                                    // a resumed session is still subscribed to its filters
+                                    let subscribed = event
+                                        .metadata
+                                        .as_ref()
+                                        .and_then(|m| m.get("filters"))
+                                        .and_then(Value::as_array)
+                                        .map(|filters| {
+                                            filters
+                                                .iter()
+                                                .filter_map(|f| f.as_str().map(|f| f.to_owned()))
+                                                .collect::<HashSet<_>>()
+                                        })
+                                        .unwrap_or_default();
+
+                                    let filters = {
+                                        self.listeners
+                                            .borrow()
+                                            .iter()
+                                            .map(|(_, f, _)| f.to_owned())
+                                            .collect::<HashSet<_>>()
+                                    };
+
+                                    for filter in filters.difference(&subscribed) {
+                                        if let Err(e) = fetcher.fetch(client_id, "subscribe", filter).await {
+                                            error!("{e}");
+                                        }
                                     }
 
-                                    if let Err(e) = fetcher.fetch(client_id, "subscribe", &filter).await {
-                                        error!("{e}");
+                                    for filter in subscribed.difference(&filters) {
+                                        if let Err(e) = fetcher.fetch(client_id, "unsubscribe", filter).await {
+                                            error!("{e}");
+                                        }
                                     }
-
-                                    subscribed.insert(filter);
                                 }
                             }
+                            ("$SYS/reconnect", _) => {
+                                retry = event.data.as_u64().unwrap_or(retry);
+                            }
+                            _ => {}
                         }
 
                         let listeners_fut = {
                             let mut listeners_fut = Vec::new();
-                            for (_, filter, listener) in listeners.borrow().iter() {
+                            for (_, filter, listener) in self.listeners.borrow().iter() {
                                 let filters = match &event.filters {
                                     Some(v) => v,
                                     _ => continue,
@@ -152,37 +226,52 @@ impl Client {
 
                         futures::future::join_all(listeners_fut).await;
                     }
-                });
-            }
-        }
 
-        Ok(self)
-    }
+                    // the browser would reconnect without the resume token
+                    source.close();
+                    TimeoutFuture::new(retry as u32).await;
 
-    pub fn endpoint(mut self, v: impl Into<String>) -> Self {
-        self.endpoint = v.into();
+                    // resuming a session bound to a user needs their token, an
+                    // `EventSource` can only send it in the URL where proxies and
+                    // access logs see it: use short-lived tokens and keep
+                    // query strings out of the logs
+                    let access_token = match resume_token {
+                        Some(_) => fetcher.access_token().await,
+                        None => None,
+                    };
 
-        self
-    }
+                    let query = resume_token
+                        .iter()
+                        .map(|token| format!("resume_token={token}"))
+                        .chain(access_token.iter().map(|token| format!("access_token={token}")))
+                        .chain(last_event_id.iter().map(|id| format!("last_event_id={id}")))
+                        .collect::<Vec<_>>();
+
+                    let url = match query.is_empty() {
+                        true => self.source_url.to_owned(),
+                        false => format!("{}?{}", self.source_url, query.join("&")),
+                    };
+
+                    let source = match EventSource::new(&url) {
+                        Ok(source) => source,
+                        Err(e) => {
+                            error!("{e}");
+                            return;
+                        }
+                    };
 
-    pub fn namespace(mut self, v: impl Into<String>) -> Self {
-        self.namespace = v.into();
+                    let mut current = self.source.borrow_mut();
 
-        self
-    }
+                    // closed while waiting
+                    if current.is_none() {
+                        source.close();
+                        return;
+                    }
 
-    pub fn close(&self) {
-        cfg_if! {
-            if #[cfg(feature = "hydrate")] {
-                if let Some(source) = self.source.borrow().as_ref() {
-                    source.clone().close();
+                    *current = Some(source);
                 }
             }
-        }
-    }
 
-    cfg_if! {
-        if #[cfg(feature = "hydrate")] {
             pub fn get_headers<Fu>(self, cb: impl Fn() -> Fu + 'static) -> Self
             where
                 Fu: Future<Output = Result<Headers>> + 'static,
@@ -212,35 +301,49 @@ impl Client {
                 Fu: Future<Output = ()> + 'static + Send,
             {
                 let filter = format!("{}/{}", self.namespace, filter.into());
-                let id = self.next_listener_id.fetch_add(1, Ordering::Relaxed);
                 let listeners = self.listeners.clone();
+                let fetcher = Fetcher::from(self);
 
-                listeners
-                    .borrow_mut()
-                    .push((id, filter.clone(), Box::new(move |e| Box::pin(listener(e)))));
+                // a malformed filter would be refused by the server, the
+                // listener is not registered
+                let id = match TopicFilter::new(filter.as_str()) {
+                    Ok(_) => Some(self.next_listener_id.fetch_add(1, Ordering::Relaxed)),
+                    Err(e) => {
+                        error!("{filter}: {e}");
+                        None
+                    }
+                };
 
-                let total_filters = listeners
-                    .borrow()
-                    .iter()
-                    .filter(|(_, f, _)| f == &filter)
-                    .count();
+                if let Some(id) = id {
+                    listeners
+                        .borrow_mut()
+                        .push((id, filter.clone(), Box::new(move |e| Box::pin(listener(e)))));
 
-                let fetcher = Fetcher::from(self);
+                    let total_filters = listeners
+                        .borrow()
+                        .iter()
+                        .filter(|(_, f, _)| f == &filter)
+                        .count();
 
-                if let (Some(client_id), 1) = (self.id.borrow().to_owned(), total_filters) {
-                    let filter = filter.clone();
-                    let fetcher = fetcher.clone();
+                    if let (Some(client_id), 1) = (self.id.borrow().to_owned(), total_filters) {
+                        let filter = filter.clone();
+                        let fetcher = fetcher.clone();
 
-                    spawn_local(async move {
-                        if let Err(e) = fetcher.fetch(&client_id, "subscribe", &filter).await {
-                            error!("{e}");
-                        }
-                    });
+                        spawn_local(async move {
+                            if let Err(e) = fetcher.fetch(&client_id, "subscribe", &filter).await {
+                                error!("{e}");
+                            }
+                        });
+                    }
                 }
 
                 let client_id = self.id.clone();
 
                 move || {
+                    let Some(id) = id else {
+                        return;
+                    };
+
                     listeners.borrow_mut().retain(|l| l.0 != id);
 
                     let total_filters = listeners
@@ -311,6 +414,17 @@ cfg_if! {
 
                 Ok(res)
             }
+
+            /// Bearer token of the headers, an `EventSource` can't send headers.
+            pub async fn access_token(&self) -> Option<String> {
+                let get_headers = { self.get_headers.borrow().as_ref().map(|f| f()) };
+                let headers = get_headers?.await.ok()?;
+
+                headers
+                    .get("Authorization")?
+                    .strip_prefix("Bearer ")
+                    .map(|token| token.to_owned())
+            }
         }
 
         impl From<&Client> for Fetcher {
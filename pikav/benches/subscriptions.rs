@@ -0,0 +1,80 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use glob_match::glob_match;
+use pikav::publisher::SubscriptionIndex;
+
+fn filters(client: usize) -> Vec<String> {
+    vec![
+        format!("users/{client}/**"),
+        format!("todos/{}", client % 100),
+        "chat/*".to_owned(),
+    ]
+}
+
+fn linear_scan(clients: &[(String, Vec<String>)], topic: &str) -> usize {
+    let mut recipients = 0;
+
+    for (_, filters) in clients {
+        let matched = filters
+            .iter()
+            .filter(|f| glob_match(f, topic))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !matched.is_empty() {
+            recipients += 1;
+        }
+    }
+
+    recipients
+}
+
+fn index_scan(index: &SubscriptionIndex, topic: &str) -> usize {
+    let mut recipients = 0;
+
+    index.matches(topic).for_each(|_, filters| {
+        let matched = filters.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+
+        if !matched.is_empty() {
+            recipients += 1;
+        }
+    });
+
+    recipients
+}
+
+fn bench_matching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("subscriptions");
+
+    for size in [1_000, 10_000, 50_000] {
+        let clients = (0..size)
+            .map(|i| (format!("client-{i}"), filters(i)))
+            .collect::<Vec<_>>();
+
+        let mut index = SubscriptionIndex::default();
+
+        for (id, filters) in clients.iter() {
+            for filter in filters {
+                index.insert(filter, id);
+            }
+        }
+
+        for topic in ["todos/42", "users/7/todos/1", "chat/general"] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("linear/{topic}"), size),
+                &topic,
+                |b, topic| b.iter(|| linear_scan(&clients, black_box(topic))),
+            );
+
+            group.bench_with_input(
+                BenchmarkId::new(format!("index/{topic}"), size),
+                &topic,
+                |b, topic| b.iter(|| index_scan(&index, black_box(topic))),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_matching);
+criterion_main!(benches);
@@ -14,6 +14,7 @@ pub struct Event<D, M> {
     pub name: String,
     pub data: D,
     pub metadata: Option<M>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub filters: Option<Vec<String>>,
 }
 
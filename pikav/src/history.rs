@@ -0,0 +1,622 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::RwLock;
+
+use crate::{
+    event::{Event, SimpleEvent},
+    topic::filter_match,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Storage(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Payload {
+    Simple(SimpleEvent),
+    Event(Event<Value, Value>),
+}
+
+impl Payload {
+    /// An event without data, publishing it retained clears the retained event.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Payload::Simple(event) => event.data.is_empty(),
+            Payload::Event(event) => event.data.is_null(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub id: u64,
+    pub user_id: String,
+    /// Users also receiving the event, besides `user_id`.
+    #[serde(default)]
+    pub user_ids: Vec<String>,
+    /// Sessions in any of these audiences also receive the event.
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// This session does not receive the event, e.g. the one that sent it.
+    #[serde(default)]
+    pub exclude_client_id: Option<String>,
+    pub created_at: u64,
+    pub payload: Payload,
+}
+
+impl Record {
+    pub fn new(id: u64, user_id: impl Into<String>, payload: Payload) -> Self {
+        Self {
+            id,
+            user_id: user_id.into(),
+            user_ids: Vec::new(),
+            audiences: Vec::new(),
+            exclude_client_id: None,
+            created_at: now(),
+            payload,
+        }
+    }
+
+    pub fn recipients(mut self, user_ids: Vec<String>, audiences: Vec<String>) -> Self {
+        self.user_ids = user_ids;
+        self.audiences = audiences;
+
+        self
+    }
+
+    pub fn exclude_client_id(mut self, value: Option<String>) -> Self {
+        self.exclude_client_id = value;
+
+        self
+    }
+
+    /// Whether a session of `user_id` in `audiences` receives the event.
+    pub fn is_for(&self, user_id: &str, audiences: &[String]) -> bool {
+        self.user_id == "*"
+            || (!user_id.is_empty()
+                && (self.user_id == user_id || self.user_ids.iter().any(|id| id == user_id)))
+            || self
+                .audiences
+                .iter()
+                .any(|audience| audiences.contains(audience))
+    }
+
+    /// Retained events replace the previous one of their topic sent to the
+    /// same recipients.
+    pub fn retain_key(&self) -> (String, String) {
+        let mut user_ids = self.user_ids.clone();
+        let mut audiences = self.audiences.clone();
+        user_ids.sort();
+        audiences.sort();
+
+        let recipients =
+            serde_json::to_string(&(&self.user_id, user_ids, audiences)).unwrap_or_default();
+
+        (recipients, self.topic().to_owned())
+    }
+
+    pub fn topic(&self) -> &str {
+        match &self.payload {
+            Payload::Simple(event) => &event.topic,
+            Payload::Event(event) => &event.topic,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TopicRetention {
+    pub filter: String,
+    pub max_events: Option<usize>,
+    pub max_age: Option<u64>,
+}
+
+/// Limits applied to each topic, `max_age` is in seconds.
+/// The first entry of `topics` matching a topic overrides the defaults.
+/// `max_topics` keeps the most recently published topics, the others are
+/// removed. A limit left unset takes its default, `~` removes it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    pub max_events: Option<usize>,
+    pub max_age: Option<u64>,
+    pub max_topics: Option<usize>,
+    pub topics: Vec<TopicRetention>,
+}
+
+impl Retention {
+    pub fn limits(&self, topic: &str) -> (Option<usize>, Option<u64>) {
+        match self.topics.iter().find(|t| filter_match(&t.filter, topic)) {
+            Some(t) => (t.max_events, t.max_age),
+            None => (self.max_events, self.max_age),
+        }
+    }
+
+    fn is_expired(&self, record: &Record, now: u64) -> bool {
+        match self.limits(record.topic()).1 {
+            Some(max_age) => record.created_at + max_age < now,
+            None => false,
+        }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_events: Some(100),
+            max_age: Some(24 * 60 * 60),
+            max_topics: Some(10_000),
+            topics: Vec::new(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait History: Send + Sync {
+    /// Highest record id stored, used to resume id generation after a restart.
+    fn last_id(&self) -> u64;
+
+    async fn push(&self, records: Vec<Record>) -> Result<(), Error>;
+
+    /// Records of topics matching `filter` that a session of `user_id` in
+    /// `audiences` receives, published after `last_event_id` and sorted by id.
+    async fn find(
+        &self,
+        filter: &str,
+        user_id: &str,
+        audiences: &[String],
+        last_event_id: u64,
+    ) -> Result<Vec<Record>, Error>;
+
+    /// Removes records that exceed the retention.
+    async fn purge(&self) -> Result<(), Error>;
+}
+
+#[derive(Default)]
+pub struct MemoryHistory {
+    retention: Retention,
+    topics: RwLock<HashMap<String, VecDeque<Record>>>,
+}
+
+impl MemoryHistory {
+    pub fn new(retention: Retention) -> Self {
+        Self {
+            retention,
+            topics: RwLock::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl History for MemoryHistory {
+    fn last_id(&self) -> u64 {
+        0
+    }
+
+    async fn push(&self, records: Vec<Record>) -> Result<(), Error> {
+        let mut topics = self.topics.write().await;
+
+        for record in records {
+            let max_events = self.retention.limits(record.topic()).0;
+            let topic = topics.entry(record.topic().to_owned()).or_default();
+
+            topic.push_back(record);
+
+            if let Some(max_events) = max_events {
+                while topic.len() > max_events {
+                    topic.pop_front();
+                }
+            }
+        }
+
+        if let Some(max_topics) = self.retention.max_topics {
+            while topics.len() > max_topics {
+                // the last record of a topic is its most recent one
+                let Some(oldest) = topics
+                    .iter()
+                    .min_by_key(|(_, records)| records.back().map(|r| r.id))
+                    .map(|(topic, _)| topic.to_owned())
+                else {
+                    break;
+                };
+
+                topics.remove(&oldest);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn find(
+        &self,
+        filter: &str,
+        user_id: &str,
+        audiences: &[String],
+        last_event_id: u64,
+    ) -> Result<Vec<Record>, Error> {
+        let topics = self.topics.read().await;
+        let now = now();
+
+        let mut records = topics
+            .iter()
+            .filter(|(topic, _)| filter_match(filter, topic))
+            .flat_map(|(_, records)| records.iter())
+            .filter(|r| {
+                r.id > last_event_id
+                    && r.is_for(user_id, audiences)
+                    && !self.retention.is_expired(r, now)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        records.sort_by_key(|r| r.id);
+
+        Ok(records)
+    }
+
+    async fn purge(&self) -> Result<(), Error> {
+        let mut topics = self.topics.write().await;
+        let now = now();
+
+        for records in topics.values_mut() {
+            records.retain(|r| !self.retention.is_expired(r, now));
+        }
+
+        topics.retain(|_, records| !records.is_empty());
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteHistory;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use async_trait::async_trait;
+    use rusqlite::{params, Connection};
+    use std::sync::{Arc, Mutex};
+
+    use super::{filter_match, now, Error, History, Record, Retention};
+    use crate::topic::literal_prefix;
+
+    impl From<rusqlite::Error> for Error {
+        fn from(e: rusqlite::Error) -> Self {
+            Error::Storage(e.to_string())
+        }
+    }
+
+    impl From<serde_json::Error> for Error {
+        fn from(e: serde_json::Error) -> Self {
+            Error::Storage(e.to_string())
+        }
+    }
+
+    pub struct SqliteHistory {
+        retention: Retention,
+        conn: Arc<Mutex<Connection>>,
+        last_id: u64,
+    }
+
+    impl SqliteHistory {
+        pub fn open(path: &str, retention: Retention) -> Result<Self, Error> {
+            let conn = Connection::open(path)?;
+
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS history (
+                    id INTEGER PRIMARY KEY,
+                    topic TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    payload TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS history_topic ON history (topic, id);",
+            )?;
+
+            // tables created before recipients were stored
+            if conn.prepare("SELECT recipients FROM history").is_err() {
+                conn.execute(
+                    "ALTER TABLE history ADD COLUMN recipients TEXT NOT NULL DEFAULT ''",
+                    [],
+                )?;
+            }
+
+            // tables created before the excluded session was stored
+            if conn
+                .prepare("SELECT exclude_client_id FROM history")
+                .is_err()
+            {
+                conn.execute("ALTER TABLE history ADD COLUMN exclude_client_id TEXT", [])?;
+            }
+
+            let last_id: Option<i64> =
+                conn.query_row("SELECT MAX(id) FROM history", [], |row| row.get(0))?;
+
+            Ok(Self {
+                retention,
+                conn: Arc::new(Mutex::new(conn)),
+                last_id: last_id.unwrap_or_default() as u64,
+            })
+        }
+
+        async fn blocking<R: Send + 'static>(
+            &self,
+            f: impl FnOnce(&mut Connection, &Retention) -> Result<R, Error> + Send + 'static,
+        ) -> Result<R, Error> {
+            let conn = self.conn.clone();
+            let retention = self.retention.clone();
+
+            tokio::task::spawn_blocking(move || {
+                let mut conn = conn.lock().map_err(|e| Error::Storage(e.to_string()))?;
+
+                f(&mut conn, &retention)
+            })
+            .await
+            .map_err(|e| Error::Storage(e.to_string()))?
+        }
+    }
+
+    fn trim(conn: &Connection, retention: &Retention, topic: &str) -> Result<(), Error> {
+        let (max_events, max_age) = retention.limits(topic);
+
+        if let Some(max_events) = max_events {
+            conn.execute(
+                "DELETE FROM history WHERE topic = ?1 AND id NOT IN
+                    (SELECT id FROM history WHERE topic = ?1 ORDER BY id DESC LIMIT ?2)",
+                params![topic, max_events as i64],
+            )?;
+        }
+
+        if let Some(max_age) = max_age {
+            conn.execute(
+                "DELETE FROM history WHERE topic = ?1 AND created_at < ?2",
+                params![topic, now().saturating_sub(max_age) as i64],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[async_trait]
+    impl History for SqliteHistory {
+        fn last_id(&self) -> u64 {
+            self.last_id
+        }
+
+        async fn push(&self, records: Vec<Record>) -> Result<(), Error> {
+            self.blocking(move |conn, retention| {
+                let tx = conn.transaction()?;
+                let mut topics = Vec::new();
+
+                for record in records {
+                    // empty unless the event has other recipients than `user_id`
+                    let recipients = match record.user_ids.is_empty() && record.audiences.is_empty()
+                    {
+                        true => String::new(),
+                        false => serde_json::to_string(&(&record.user_ids, &record.audiences))?,
+                    };
+
+                    tx.execute(
+                        "INSERT INTO history
+                            (id, topic, user_id, recipients, exclude_client_id, created_at, payload)
+                            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                        params![
+                            record.id as i64,
+                            record.topic(),
+                            record.user_id,
+                            recipients,
+                            record.exclude_client_id,
+                            record.created_at as i64,
+                            serde_json::to_string(&record.payload)?
+                        ],
+                    )?;
+
+                    if !topics.iter().any(|t| t == record.topic()) {
+                        topics.push(record.topic().to_owned());
+                    }
+                }
+
+                for topic in topics {
+                    trim(&tx, retention, &topic)?;
+                }
+
+                tx.commit()?;
+
+                Ok(())
+            })
+            .await
+        }
+
+        async fn find(
+            &self,
+            filter: &str,
+            user_id: &str,
+            audiences: &[String],
+            last_event_id: u64,
+        ) -> Result<Vec<Record>, Error> {
+            let filter = filter.to_owned();
+            let user_id = user_id.to_owned();
+            let audiences = audiences.to_vec();
+            // ids are stored as i64, a larger one would wrap and replay everything
+            let last_event_id = last_event_id.min(i64::MAX as u64) as i64;
+
+            self.blocking(move |conn, retention| {
+                let now = now();
+                // the prefix has no glob character, `GLOB 'prefix*'` is a range
+                // of the topic index, the planner would otherwise walk ids
+                let topics = format!("{}*", literal_prefix(&filter));
+                let mut stmt = conn.prepare(
+                    "SELECT id, user_id, recipients, exclude_client_id, created_at, payload
+                        FROM history INDEXED BY history_topic
+                        WHERE topic GLOB ?1 AND id > ?2
+                            AND (user_id = '*' OR user_id = ?3 OR recipients != '')
+                        ORDER BY id",
+                )?;
+
+                let rows = stmt.query_map(params![topics, last_event_id, user_id], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, i64>(4)?,
+                        row.get::<_, String>(5)?,
+                    ))
+                })?;
+
+                let mut records = Vec::new();
+
+                for row in rows {
+                    let (id, record_user_id, recipients, exclude_client_id, created_at, payload) =
+                        row?;
+                    let (user_ids, record_audiences) = match recipients.is_empty() {
+                        true => Default::default(),
+                        false => serde_json::from_str(&recipients)?,
+                    };
+
+                    let record = Record {
+                        id: id as u64,
+                        user_id: record_user_id,
+                        user_ids,
+                        audiences: record_audiences,
+                        exclude_client_id,
+                        created_at: created_at as u64,
+                        payload: serde_json::from_str(&payload)?,
+                    };
+
+                    if filter_match(&filter, record.topic())
+                        && record.is_for(&user_id, &audiences)
+                        && !retention.is_expired(&record, now)
+                    {
+                        records.push(record);
+                    }
+                }
+
+                Ok(records)
+            })
+            .await
+        }
+
+        async fn purge(&self) -> Result<(), Error> {
+            self.blocking(|conn, retention| {
+                let topics = {
+                    let mut stmt = conn.prepare("SELECT DISTINCT topic FROM history")?;
+                    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+                    rows.collect::<Result<Vec<_>, _>>()?
+                };
+
+                for topic in topics {
+                    trim(conn, retention, &topic)?;
+                }
+
+                if let Some(max_topics) = retention.max_topics {
+                    conn.execute(
+                        "DELETE FROM history WHERE topic NOT IN
+                            (SELECT topic FROM history GROUP BY topic
+                                ORDER BY MAX(id) DESC LIMIT ?1)",
+                        params![max_topics as i64],
+                    )?;
+                }
+
+                Ok(())
+            })
+            .await
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u64, topic: &str) -> Record {
+        Record::new(
+            id,
+            "*",
+            Payload::Simple(SimpleEvent {
+                topic: topic.to_owned(),
+                event: "Created".to_owned(),
+                data: id.to_string(),
+            }),
+        )
+    }
+
+    fn ids(records: Vec<Record>) -> Vec<u64> {
+        records.into_iter().map(|r| r.id).collect()
+    }
+
+    #[tokio::test]
+    async fn max_age() {
+        let history = MemoryHistory::new(Retention {
+            max_age: Some(60),
+            ..Default::default()
+        });
+
+        let mut expired = record(1, "todos/1");
+        expired.created_at = now() - 61;
+
+        history
+            .push(vec![expired, record(2, "todos/1"), record(3, "todos/2")])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            ids(history.find("todos/*", "", &[], 0).await.unwrap()),
+            [2, 3]
+        );
+
+        let mut expired = record(4, "todos/3");
+        expired.created_at = 0;
+        history.push(vec![expired]).await.unwrap();
+        history.purge().await.unwrap();
+
+        let topics = history.topics.read().await;
+        assert_eq!(topics["todos/1"].len(), 1);
+        assert!(!topics.contains_key("todos/3"));
+    }
+
+    #[tokio::test]
+    async fn max_topics() {
+        let history = MemoryHistory::new(Retention {
+            max_topics: Some(2),
+            ..Default::default()
+        });
+
+        history
+            .push(vec![record(1, "todos/1"), record(2, "todos/2")])
+            .await
+            .unwrap();
+        history.push(vec![record(3, "todos/1")]).await.unwrap();
+        history.push(vec![record(4, "todos/3")]).await.unwrap();
+
+        // `todos/2` is the least recently published
+        assert_eq!(
+            ids(history.find("todos/*", "", &[], 0).await.unwrap()),
+            [1, 3, 4]
+        );
+    }
+
+    #[test]
+    fn default_limits() {
+        let retention = Retention::default();
+        assert!(retention.max_age.is_some());
+        assert!(retention.max_topics.is_some());
+
+        let retention: Retention = serde_json::from_str(r#"{"max_age": null}"#).unwrap();
+        assert_eq!(retention.max_age, None);
+        assert_eq!(retention.max_events, Some(100));
+    }
+}
@@ -1,7 +1,11 @@
 #[cfg(feature = "event")]
 mod event;
 #[cfg(feature = "publisher")]
+pub mod history;
+#[cfg(feature = "publisher")]
 pub mod publisher;
+mod topic;
 
 #[cfg(feature = "event")]
 pub use event::{Event, SimpleEvent};
+pub use topic::{FilterSyntax, Topic, TopicError, TopicFilter};
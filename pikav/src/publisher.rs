@@ -1,198 +1,726 @@
-use futures::StreamExt;
-use glob_match::glob_match;
+mod channel;
+mod frame;
+mod index;
+mod predicate;
+mod presence;
+mod quota;
+mod resume;
+
+use bytes::Bytes;
+use metrics::{counter, gauge};
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::RandomState, HashMap, HashSet},
     fmt::Debug,
-    sync::Arc,
-    time::Duration,
-};
-use tokio::{
+    hash::{BuildHasher, Hasher},
     sync::{
-        mpsc::{channel, error::TrySendError, Sender},
-        RwLock,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, OnceLock,
     },
-    time::{interval_at, Instant},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{broadcast, mpsc::error::TrySendError, RwLock},
+    time::{interval_at, sleep, Instant},
 };
 
-pub use tokio::sync::mpsc::Receiver;
-
-use crate::event::{Event, SimpleEvent};
+pub use channel::{channel, Overflow, Receiver, Sender};
+pub use frame::Frame;
+pub use index::{Matches, SubscriptionIndex};
+pub use predicate::{ParseError, Predicate};
+pub use presence::Presence;
+pub use quota::Quotas;
+
+use quota::RateLimiter;
+use resume::ResumeTokens;
+
+use crate::{
+    event::{Event, SimpleEvent},
+    history::{History, MemoryHistory, Payload, Record},
+    topic::{filter_match, syntax_of},
+    FilterSyntax, TopicFilter,
+};
 
 #[derive(Debug)]
 pub enum Error {
     SessionNotFound,
+    /// The session was created by, or is bound to, another user.
+    UserMismatch,
+    /// The session buffer could not take the session id.
+    Full,
+    /// The user already has this many sessions.
+    TooManySessions(usize),
+    /// The session already has this many filters.
+    TooManyFilters(usize),
+    /// The user already subscribed this many times in the last minute.
+    TooManySubscribes(u32),
+    /// The publisher is draining before shutdown and takes no new session.
+    Draining,
+    /// The filter is not written with the only syntax accepted.
+    Syntax(FilterSyntax),
 }
 
 #[derive(Debug)]
-pub struct Client<T: From<String> + Clone + Debug + Sync + Send + 'static> {
+pub struct Client<T: From<Frame> + Clone + Debug + Sync + Send + 'static> {
     user_id: RwLock<Option<String>>,
     sender: Sender<T>,
     filters: RwLock<Vec<String>>,
+    predicates: RwLock<HashMap<String, Predicate>>,
+    /// Groups of the user, e.g. `org:acme`, events can be published to.
+    audiences: RwLock<Vec<String>>,
+    last_event_id: Option<u64>,
+    /// Last event id when the session connected, `last_event_id` only replays
+    /// the events missed until then.
+    connected_id: u64,
+    failures: AtomicU32,
+    created_at: SystemTime,
+    /// Secret sent with the session id, resumes the session once its connection closed.
+    resume_token: String,
 }
 
-impl<T: From<String> + Clone + Debug + Sync + Send + 'static> Client<T> {
+impl<T: From<Frame> + Clone + Debug + Sync + Send + 'static> Client<T> {
     pub fn new(sender: Sender<T>) -> Self {
         Self {
             sender,
             filters: RwLock::new(Vec::new()),
+            predicates: RwLock::default(),
+            audiences: RwLock::default(),
             user_id: RwLock::new(None),
+            last_event_id: None,
+            connected_id: 0,
+            failures: AtomicU32::new(0),
+            created_at: SystemTime::now(),
+            resume_token: String::new(),
         }
     }
 
-    pub async fn update_user_id(&self, id: String) -> bool {
+    pub fn last_event_id(mut self, value: Option<u64>) -> Self {
+        self.last_event_id = value;
+
+        self
+    }
+
+    pub fn connected_id(mut self, value: u64) -> Self {
+        self.connected_id = value;
+
+        self
+    }
+
+    pub fn user_id(mut self, value: Option<String>) -> Self {
+        self.user_id = RwLock::new(value);
+
+        self
+    }
+
+    pub fn audiences(mut self, value: Vec<String>) -> Self {
+        self.audiences = RwLock::new(value);
+
+        self
+    }
+
+    pub fn resume_token(mut self, value: String) -> Self {
+        self.resume_token = value;
+
+        self
+    }
+
+    /// Restores the user, audiences, filters and predicates of a suspended
+    /// session.
+    fn restore(mut self, suspended: Suspended) -> Self {
+        if suspended.user_id.is_some() {
+            self.user_id = RwLock::new(suspended.user_id);
+        }
+
+        if self.audiences.get_mut().is_empty() {
+            self.audiences = RwLock::new(suspended.audiences);
+        }
+
+        self.filters = RwLock::new(suspended.filters);
+        self.predicates = RwLock::new(suspended.predicates);
+        self.created_at = suspended.created_at;
+
+        self
+    }
+
+    /// Binds the session to `id` if it has no user yet, a session can never
+    /// change user once bound.
+    pub async fn bind_user_id(&self, id: &str) -> Result<(), Error> {
         {
             let current_id = self.user_id.read().await;
 
-            if current_id.eq(&Some(id.to_owned())) {
-                return false;
+            match current_id.as_deref() {
+                Some(current_id) if current_id == id => return Ok(()),
+                Some(_) => return Err(Error::UserMismatch),
+                None => {}
             }
         }
 
         let mut current_id = self.user_id.write().await;
-        *current_id = Some(id);
 
-        let mut filters = self.filters.write().await;
-        filters.clear();
+        match current_id.as_deref() {
+            Some(current_id) if current_id != id => Err(Error::UserMismatch),
+            _ => {
+                *current_id = Some(id.to_owned());
 
-        true
+                Ok(())
+            }
+        }
+    }
+
+    async fn is_owned_by(&self, id: &str) -> bool {
+        self.user_id
+            .read()
+            .await
+            .as_deref()
+            .map(|current_id| current_id == id)
+            .unwrap_or(true)
+    }
+
+    /// Sends an SSE comment to keep the connection open, a full buffer only
+    /// counts as a failure.
+    pub fn heartbeat(&self) {
+        match self
+            .sender
+            .try_send(Frame::from_static(b": ping\n\n").into())
+        {
+            Ok(_) => self.failures.store(0, Ordering::Relaxed),
+            Err(_) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
     }
 
-    pub fn is_stale(&self) -> bool {
-        self.sender
-            .try_send("data: ping\n\n".to_owned().into())
-            .is_err()
+    pub fn is_stale(&self, max_failures: u32) -> bool {
+        self.sender.is_closed() || self.failures.load(Ordering::Relaxed) >= max_failures
     }
 
     pub async fn insert(&self, filter: String) -> bool {
+        self.try_insert(filter, None).await.unwrap_or_default()
+    }
+
+    /// Inserts `filter` unless the session already has `max` filters.
+    pub async fn try_insert(&self, filter: String, max: Option<usize>) -> Result<bool, Error> {
         {
             let filters = self.filters.read().await;
 
             if filters.iter().any(|f| f == &filter) {
-                return false;
+                return Ok(false);
             }
         }
 
         let mut filters = self.filters.write().await;
-        filters.push(filter);
 
-        true
+        if filters.iter().any(|f| f == &filter) {
+            return Ok(false);
+        }
+
+        match max {
+            Some(max) if filters.len() >= max => Err(Error::TooManyFilters(max)),
+            _ => {
+                filters.push(filter);
+
+                Ok(true)
+            }
+        }
     }
 
     pub async fn remove(&self, filter: String) -> bool {
         {
             let filters = self.filters.read().await;
 
-            if filters.iter().any(|f| f == &filter) {
+            if !filters.iter().any(|f| f == &filter) {
                 return filters.is_empty();
             }
         }
 
+        self.predicates.write().await.remove(&filter);
+
         let mut filters = self.filters.write().await;
         filters.retain(|f| f != &filter);
 
         filters.is_empty()
     }
 
-    pub fn send_event_session_id(&self, id: impl Into<String>) -> Result<(), TrySendError<T>> {
-        self.send_event(Event::new("$SYS/session", "Created", id.into()))
+    /// Replaces the predicate events matching `filter` must also satisfy.
+    pub async fn set_predicate(&self, filter: &str, predicate: Option<Predicate>) {
+        if predicate.is_none() && self.predicates.read().await.is_empty() {
+            return;
+        }
+
+        let mut predicates = self.predicates.write().await;
+
+        match predicate {
+            Some(predicate) => predicates.insert(filter.to_owned(), predicate),
+            None => predicates.remove(filter),
+        };
+    }
+
+    /// Filters of `filters` without a predicate or whose predicate accepts the event.
+    async fn accepted<'a, 'd>(
+        &self,
+        filters: &[&'a str],
+        name: &str,
+        data: impl Fn() -> &'d Value,
+    ) -> Vec<&'a str> {
+        let predicates = self.predicates.read().await;
+
+        if predicates.is_empty() {
+            return filters.to_vec();
+        }
+
+        filters
+            .iter()
+            .filter(|filter| {
+                predicates
+                    .get(**filter)
+                    .map(|predicate| predicate.matches(name, data()))
+                    .unwrap_or(true)
+            })
+            .copied()
+            .collect()
+    }
+
+    pub fn send_event_session_id(
+        &self,
+        event_id: u64,
+        id: impl Into<String>,
+    ) -> Result<(), TrySendError<T>> {
+        let event = Event::with_metadata("$SYS/session", "Created", id.into())
+            .metadata(json!({ "resume_token": self.resume_token }));
+
+        self.send_event(event_id, event)
+    }
+
+    /// Tells the client its session was resumed with `filters` still subscribed.
+    pub fn send_event_session_resumed(
+        &self,
+        event_id: u64,
+        id: impl Into<String>,
+        filters: &[String],
+    ) -> Result<(), TrySendError<T>> {
+        let event = Event::with_metadata("$SYS/session", "Resumed", id.into())
+            .metadata(json!({ "resume_token": self.resume_token, "filters": filters }));
+
+        self.send_event(event_id, event)
     }
 
     pub fn send_event<D: Serialize, M: Serialize>(
         &self,
+        id: u64,
         event: Event<D, M>,
     ) -> Result<(), TrySendError<T>> {
-        let data = serde_json::to_string(&event).unwrap();
+        let data = serde_json::to_vec(&event).unwrap();
 
-        self.send(SimpleEvent {
-            topic: event.topic,
-            event: "message".to_owned(),
-            data,
+        self.send_frame(&event.topic, Frame::new(id, "message", data.into()))
+    }
+
+    pub fn send(&self, id: u64, event: SimpleEvent) -> Result<(), TrySendError<T>> {
+        self.send_frame(
+            &event.topic,
+            Frame::new(id, &event.event, event.data.into()),
+        )
+    }
+
+    pub fn send_frame(&self, topic: &str, frame: Frame) -> Result<(), TrySendError<T>> {
+        self.sender.try_send_topic(topic, frame.into(), |dropped| {
+            // every filter of the session, its listeners all missed events; a
+            // subscribe in progress holds the lock and leaves them out
+            let filters = self
+                .filters
+                .try_read()
+                .map(|filters| filters.clone())
+                .unwrap_or_default();
+            let event = Event::new("$SYS/overflow", "Dropped", dropped).filters(filters);
+            let data = serde_json::to_vec(&event).unwrap();
+
+            Frame::without_id("message", data.into()).into()
         })
     }
 
-    pub fn send(&self, event: SimpleEvent) -> Result<(), TrySendError<T>> {
-        self.sender.clone().try_send(
-            [
-                "event: ",
-                event.event.as_ref(),
-                "\n",
-                "data: ",
-                event.data.as_ref(),
-                "\n\n",
-            ]
-            .concat()
-            .into(),
+    pub fn dropped(&self) -> u64 {
+        self.sender.dropped()
+    }
+
+    /// Asks the client to reconnect in `retry`, to another node once this one is gone.
+    pub fn send_reconnect(&self, retry: Duration) -> Result<(), TrySendError<T>> {
+        let retry = retry.as_millis() as u64;
+        let data = serde_json::to_vec(&Event::new("$SYS/reconnect", "Reconnect", retry)).unwrap();
+
+        self.sender.try_send(
+            Frame::without_id("message", data.into())
+                .retry(retry)
+                .into(),
         )
     }
 
+    async fn info(&self, id: &str) -> SessionInfo {
+        SessionInfo {
+            id: id.to_owned(),
+            user_id: self.user_id.read().await.to_owned(),
+            filters: self.filters.read().await.to_owned(),
+            created_at: self
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            queue_len: self.sender.len(),
+        }
+    }
+
     pub async fn filter_send_event<D: Serialize, M: Serialize>(
         &self,
+        id: u64,
         event: Event<D, M>,
     ) -> Result<(), TrySendError<T>> {
         let rw_filters = self.filters.read().await;
 
         let filters = rw_filters
             .iter()
-            .filter_map(|filter| match glob_match(filter, &event.topic) {
-                true => Some(filter.to_owned()),
-                false => None,
-            })
+            .filter(|filter| filter_match(filter, &event.topic))
+            .map(String::as_str)
             .collect::<Vec<_>>();
 
+        let filters = match filters.is_empty() {
+            true => filters,
+            false => {
+                let data = OnceLock::new();
+
+                self.accepted(&filters, &event.name, || {
+                    data.get_or_init(|| serde_json::to_value(&event.data).unwrap_or_default())
+                })
+                .await
+            }
+        };
+
         if !filters.is_empty() {
-            self.send_event(event.filters(filters))?;
+            let filters = filters.into_iter().map(str::to_owned).collect();
+
+            self.send_event(id, event.filters(filters))?;
         }
 
         Ok(())
     }
 
-    pub async fn filter_send(&self, event: SimpleEvent) -> Result<(), TrySendError<T>> {
+    pub async fn filter_send(&self, id: u64, event: SimpleEvent) -> Result<(), TrySendError<T>> {
         let rw_filters = self.filters.read().await;
 
         let filters = rw_filters
             .iter()
-            .filter_map(|filter| match glob_match(filter, &event.topic) {
-                true => Some(filter.to_owned()),
-                false => None,
-            })
+            .filter(|filter| filter_match(filter, &event.topic))
+            .map(String::as_str)
             .collect::<Vec<_>>();
 
+        let filters = match filters.is_empty() {
+            true => filters,
+            false => {
+                let data = OnceLock::new();
+
+                self.accepted(&filters, &event.event, || {
+                    data.get_or_init(|| simple_data(&event.data))
+                })
+                .await
+            }
+        };
+
         if !filters.is_empty() {
-            self.send(event)?;
+            self.send(id, event)?;
         }
 
         Ok(())
     }
+
+    /// Sends the records matching `filter`, or any filter of the session when
+    /// `None`, but the ones excluding the session `id`.
+    async fn replay(&self, id: &str, filter: Option<&str>, records: &[Record]) {
+        let filters = self.filters.read().await;
+        let predicates = self.predicates.read().await;
+
+        for record in records {
+            if filter.is_some_and(|filter| !filter_match(filter, record.topic()))
+                || record.exclude_client_id.as_deref() == Some(id)
+            {
+                continue;
+            }
+
+            let data = OnceLock::new();
+            let name = match &record.payload {
+                Payload::Simple(event) => event.event.as_str(),
+                Payload::Event(event) => event.name.as_str(),
+            };
+
+            let accepts = |f: &str| {
+                predicates
+                    .get(f)
+                    .map(|predicate| {
+                        let data = data.get_or_init(|| match &record.payload {
+                            Payload::Simple(event) => simple_data(&event.data),
+                            Payload::Event(event) => event.data.clone(),
+                        });
+
+                        predicate.matches(name, data)
+                    })
+                    .unwrap_or(true)
+            };
+
+            let matched = filters
+                .iter()
+                .filter(|f| filter_match(f, record.topic()) && accepts(f))
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+
+            // Events matching a filter subscribed before were already replayed
+            // (or delivered live) for that filter.
+            let is_missed = match filter {
+                Some(filter) => matched == [filter],
+                None => !matched.is_empty(),
+            };
+
+            if !is_missed {
+                continue;
+            }
+
+            let res = match &record.payload {
+                Payload::Simple(event) => self.send(record.id, event.clone()),
+                Payload::Event(event) => {
+                    let payload = Bytes::from(serde_json::to_vec(event).unwrap());
+
+                    self.send_frame(&event.topic, Frame::message(record.id, &payload, &matched))
+                }
+            };
+
+            if res.is_err() {
+                break;
+            }
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message<E> {
     pub event: E,
     pub user_id: String,
+    /// Keeps the event as the last one of its topic, delivered to later subscribers.
+    #[serde(default)]
+    pub retain: bool,
+    /// Users also receiving the event, besides `user_id`.
+    #[serde(default)]
+    pub user_ids: Vec<String>,
+    /// Sessions in any of these audiences also receive the event.
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// Only sends the event to this session, which still needs a filter
+    /// matching the topic. Such an event is neither kept in history nor retained.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Session not sent the event, usually the one whose change produced it.
+    #[serde(default)]
+    pub exclude_client_id: Option<String>,
+}
+
+/// A session as listed by the admin API.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user_id: Option<String>,
+    pub filters: Vec<String>,
+    /// Unix timestamp in seconds.
+    pub created_at: u64,
+    /// Frames buffered and not yet read by the connection.
+    pub queue_len: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ClientOptions {
+    pub send_id: bool,
+    pub last_event_id: Option<u64>,
+    /// Binds the session to this user, otherwise to the first user subscribing.
+    pub user_id: Option<String>,
+    /// Groups of the user the session is in, see `Message::audiences`.
+    pub audiences: Vec<String>,
+    /// Token of a session whose connection closed, its id and filters are
+    /// reused when it is still suspended and bound to `user_id` if bound.
+    pub resume_token: Option<String>,
+}
+
+/// Frames of one published event sent and dropped, counted once every
+/// recipient was sent to.
+#[derive(Default)]
+struct Delivery {
+    delivered: u64,
+    dropped: u64,
+}
+
+impl Delivery {
+    fn send<T: From<Frame> + Clone + Debug + Sync + Send + 'static>(
+        &mut self,
+        client: &Client<T>,
+        topic: &str,
+        frame: Frame,
+    ) {
+        let dropped = client.dropped();
+
+        if client.send_frame(topic, frame).is_ok() {
+            self.delivered += 1;
+        }
+
+        self.dropped += client.dropped().saturating_sub(dropped);
+    }
+
+    fn count(self, topic: &str) {
+        if self.delivered > 0 {
+            counter!("pikav_events_delivered_total", "namespace" => namespace(topic))
+                .increment(self.delivered);
+        }
+
+        if self.dropped > 0 {
+            counter!("pikav_events_dropped_total", "namespace" => namespace(topic))
+                .increment(self.dropped);
+        }
+    }
+}
+
+/// A session whose connection closed, kept until it is resumed or expires.
+#[derive(Debug, Clone)]
+struct Suspended {
+    id: String,
+    resume_token: String,
+    user_id: Option<String>,
+    audiences: Vec<String>,
+    filters: Vec<String>,
+    predicates: HashMap<String, Predicate>,
+    created_at: SystemTime,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct DrainOptions {
+    /// Delay before a client reconnects.
+    pub retry: Duration,
+    /// Maximum random delay added to `retry` so clients do not all reconnect at once.
+    pub jitter: Duration,
+    /// Time given to clients to read their buffered events.
+    pub timeout: Duration,
+}
+
+impl Default for DrainOptions {
+    fn default() -> Self {
+        Self {
+            retry: Duration::from_secs(3),
+            jitter: Duration::from_secs(5),
+            timeout: Duration::from_secs(10),
+        }
+    }
 }
 
 #[derive(Clone)]
-pub struct Publisher<T: From<String> + Clone + Debug + Sync + Send + 'static> {
+pub struct PublisherOptions {
+    pub history: Arc<dyn History>,
+    pub buffer_size: usize,
+    pub overflow: Overflow,
+    pub heartbeat_interval: Duration,
+    /// Consecutive heartbeats that could not be buffered before a client is removed.
+    pub max_heartbeat_failures: u32,
+    pub quotas: Quotas,
+    /// Time a session whose connection closed can be resumed, zero disables it.
+    pub resume_grace: Duration,
+    /// Only accepts filters written with this syntax, filters without any
+    /// wildcard always are. Both are accepted when `None`.
+    pub filter_syntax: Option<FilterSyntax>,
+}
+
+impl Default for PublisherOptions {
+    fn default() -> Self {
+        Self {
+            history: Arc::new(MemoryHistory::default()),
+            buffer_size: 100,
+            overflow: Overflow::default(),
+            heartbeat_interval: Duration::from_secs(10),
+            max_heartbeat_failures: 3,
+            quotas: Quotas::default(),
+            resume_grace: Duration::from_secs(30),
+            filter_syntax: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Publisher<T: From<Frame> + Clone + Debug + Sync + Send + 'static> {
     clients: Arc<RwLock<HashMap<String, Client<T>>>>,
     user_clients: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+    audience_clients: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+    index: Arc<RwLock<SubscriptionIndex>>,
+    presence: Arc<RwLock<Presence>>,
+    /// Presence events of this node, forwarded to the other nodes.
+    presence_events: broadcast::Sender<Event<Value, Value>>,
+    /// Retained events by recipients and topic, see `Record::retain_key`.
+    retained: Arc<RwLock<HashMap<(String, String), Record>>>,
+    /// Suspended sessions by id.
+    suspended: Arc<RwLock<HashMap<String, Suspended>>>,
+    resume_tokens: ResumeTokens,
+    subscribes: Arc<RwLock<RateLimiter>>,
+    draining: Arc<AtomicBool>,
+    history: Arc<dyn History>,
+    last_id: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+    buffer_size: usize,
+    overflow: Overflow,
+    heartbeat_interval: Duration,
+    max_heartbeat_failures: u32,
+    quotas: Quotas,
+    resume_grace: Duration,
+    filter_syntax: Option<FilterSyntax>,
 }
 
-impl<T: From<String> + Clone + Debug + Sync + Send + 'static> Publisher<T> {
+impl<T: From<Frame> + Clone + Debug + Sync + Send + 'static> Publisher<T> {
+    pub fn new(options: PublisherOptions) -> Self {
+        Self {
+            clients: Arc::default(),
+            user_clients: Arc::default(),
+            audience_clients: Arc::default(),
+            index: Arc::default(),
+            presence: Arc::default(),
+            presence_events: broadcast::channel(1024).0,
+            retained: Arc::default(),
+            suspended: Arc::default(),
+            resume_tokens: ResumeTokens::default(),
+            subscribes: Arc::default(),
+            draining: Arc::default(),
+            last_id: Arc::new(AtomicU64::new(options.history.last_id())),
+            history: options.history,
+            dropped: Arc::default(),
+            buffer_size: options.buffer_size,
+            overflow: options.overflow,
+            heartbeat_interval: options.heartbeat_interval,
+            max_heartbeat_failures: options.max_heartbeat_failures,
+            quotas: options.quotas,
+            resume_grace: options.resume_grace,
+            filter_syntax: options.filter_syntax,
+        }
+    }
+
     pub fn start() -> Self {
-        let publisher = Self::default();
+        Self::start_with_options(PublisherOptions::default())
+    }
+
+    pub fn start_with_options(options: PublisherOptions) -> Self {
+        let publisher = Self::new(options);
 
         tokio::spawn({
             let publisher = publisher.clone();
 
             async move {
-                let mut interval = interval_at(Instant::now(), Duration::from_secs(10));
+                let mut interval = interval_at(Instant::now(), publisher.heartbeat_interval);
 
                 loop {
                     interval.tick().await;
 
+                    publisher.heartbeat().await;
                     publisher.remove_stale_clients().await;
+                    publisher.subscribes.write().await.purge();
+                    publisher.purge_suspended().await;
+                    publisher.history.purge().await.ok();
                 }
             }
         });
@@ -200,29 +728,303 @@ impl<T: From<String> + Clone + Debug + Sync + Send + 'static> Publisher<T> {
         publisher
     }
 
+    async fn heartbeat(&self) {
+        let clients = self.clients.read().await;
+
+        for client in clients.values() {
+            client.heartbeat();
+        }
+    }
+
     async fn remove_stale_clients(&self) {
         let ids = {
             let clients = self.clients.read().await;
-            let mut ids = Vec::new();
 
-            for (id, c) in clients.iter() {
-                if c.is_stale() {
-                    ids.push((id.to_owned(), c.user_id.read().await.to_owned()));
+            clients
+                .iter()
+                .filter(|(_, c)| c.is_stale(self.max_heartbeat_failures))
+                .map(|(id, _)| id.to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let evicted = self.remove_clients(ids, true).await;
+
+        counter!("pikav_stale_clients_evicted_total").increment(evicted as u64);
+    }
+
+    /// Removes the sessions, dropping their sender ends their stream. When
+    /// `suspend` is set, they can be resumed within the grace period.
+    async fn remove_clients(&self, ids: Vec<String>, suspend: bool) -> usize {
+        let mut events = Vec::new();
+        let mut removed = 0;
+
+        {
+            let mut clients = self.clients.write().await;
+
+            for client_id in ids {
+                let Some(client) = clients.remove(client_id.as_str()) else {
+                    continue;
+                };
+
+                removed += 1;
+                self.dropped.fetch_add(client.dropped(), Ordering::Relaxed);
+
+                let filters = client.filters.into_inner();
+                let user_id = client.user_id.into_inner();
+                let audiences = client.audiences.into_inner();
+                gauge!("pikav_subscriptions").decrement(filters.len() as f64);
+
+                self.index_audiences(&client_id, &audiences, &[]).await;
+
+                {
+                    let mut index = self.index.write().await;
+
+                    for filter in filters.iter() {
+                        index.remove(filter, &client_id);
+                    }
+                }
+
+                if let Some(user_id) = &user_id {
+                    self.remove_user_client(user_id, &client_id).await;
+                    events.extend(self.leave(user_id, &filters).await);
+                }
+
+                if suspend && !self.resume_grace.is_zero() {
+                    self.suspended.write().await.insert(
+                        client_id.to_owned(),
+                        Suspended {
+                            id: client_id,
+                            resume_token: client.resume_token,
+                            user_id,
+                            audiences,
+                            filters,
+                            predicates: client.predicates.into_inner(),
+                            created_at: client.created_at,
+                            expires_at: Instant::now() + self.resume_grace,
+                        },
+                    );
                 }
             }
 
-            ids
-        };
+            gauge!("pikav_sessions").set(clients.len() as f64);
+        }
+
+        self.send_presence(events).await;
+
+        removed
+    }
+
+    /// Stops taking new sessions and sends every client a `$SYS/reconnect`
+    /// event, then removes each client once it read its buffered events or
+    /// when `timeout` elapsed.
+    pub async fn drain(&self, options: DrainOptions) {
+        self.draining.store(true, Ordering::Relaxed);
+
+        {
+            let clients = self.clients.read().await;
+
+            for client in clients.values() {
+                let _ = client.send_reconnect(options.retry + jitter(options.jitter));
+            }
+        }
+
+        let deadline = Instant::now() + options.timeout;
+
+        loop {
+            let (ids, is_empty) = {
+                let clients = self.clients.read().await;
+
+                let ids = clients
+                    .iter()
+                    .filter(|(_, c)| Instant::now() >= deadline || c.sender.is_empty())
+                    .map(|(id, _)| id.to_owned())
+                    .collect::<Vec<_>>();
+
+                let is_empty = ids.len() == clients.len();
+
+                (ids, is_empty)
+            };
+
+            self.remove_clients(ids, false).await;
+
+            if is_empty {
+                break;
+            }
+
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Every session of this node.
+    pub async fn sessions(&self) -> Vec<SessionInfo> {
+        let clients = self.clients.read().await;
+        let mut sessions = Vec::with_capacity(clients.len());
+
+        for (id, client) in clients.iter() {
+            sessions.push(client.info(id).await);
+        }
 
-        let mut clients = self.clients.write().await;
+        sessions
+    }
 
-        for (client_id, user_id) in ids {
-            clients.remove(client_id.as_str());
+    /// Sessions of this node bound to `user_id`.
+    pub async fn user_sessions(&self, user_id: &str) -> Vec<SessionInfo> {
+        let clients = self.clients.read().await;
+        let user_clients = self.user_clients.read().await;
+        let mut sessions = Vec::new();
 
-            if let Some(user_id) = user_id {
-                self.remove_user_client(&user_id, &client_id).await;
+        for id in user_clients.get(user_id).into_iter().flatten() {
+            if let Some(client) = clients.get(id) {
+                sessions.push(client.info(id).await);
             }
         }
+
+        sessions
+    }
+
+    pub async fn disconnect(&self, client_id: &str) -> Result<(), Error> {
+        match self.remove_clients(vec![client_id.to_owned()], false).await {
+            0 => Err(Error::SessionNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Disconnects every session of `user_id` on this node, returns how many were.
+    /// Its suspended sessions can no longer be resumed.
+    pub async fn disconnect_user(&self, user_id: &str) -> usize {
+        self.suspended
+            .write()
+            .await
+            .retain(|_, session| session.user_id.as_deref() != Some(user_id));
+
+        let ids = {
+            let user_clients = self.user_clients.read().await;
+
+            user_clients
+                .get(user_id)
+                .map(|ids| ids.iter().cloned().collect())
+                .unwrap_or_default()
+        };
+
+        self.remove_clients(ids, false).await
+    }
+
+    async fn purge_suspended(&self) {
+        let now = Instant::now();
+
+        self.suspended
+            .write()
+            .await
+            .retain(|_, session| session.expires_at > now);
+    }
+
+    /// Suspends the session of `token` if its connection closed since the
+    /// last sweep, a client reconnecting within `heartbeat_interval` would
+    /// otherwise not find it suspended yet.
+    async fn suspend_closed(&self, token: &str) {
+        let Some(id) = self.resume_tokens.verify(token) else {
+            return;
+        };
+
+        let is_closed = self
+            .clients
+            .read()
+            .await
+            .get(id)
+            .is_some_and(|client| client.resume_token == token && client.sender.is_closed());
+
+        if is_closed {
+            self.remove_clients(vec![id.to_owned()], true).await;
+        }
+    }
+
+    /// Takes the session `token` was signed for, unless it expired, the token
+    /// is not the one of its last connection or the session is bound to a
+    /// user other than `user_id`. A session bound to a user is only resumed
+    /// by that user.
+    async fn take_suspended(&self, token: &str, user_id: Option<&str>) -> Option<Suspended> {
+        let id = self.resume_tokens.verify(token)?;
+        let mut suspended = self.suspended.write().await;
+        let session = suspended.get(id)?;
+
+        if session.expires_at <= Instant::now() || session.resume_token != token {
+            return None;
+        }
+
+        if session.user_id.is_some() && session.user_id.as_deref() != user_id {
+            return None;
+        }
+
+        suspended.remove(id)
+    }
+
+    /// Puts back a session taken by `take_suspended` that could not be resumed.
+    async fn put_suspended(&self, session: Option<Suspended>) {
+        if let Some(session) = session {
+            self.suspended
+                .write()
+                .await
+                .insert(session.id.to_owned(), session);
+        }
+    }
+
+    async fn leave(&self, user_id: &str, filters: &[String]) -> Vec<Event<Value, Value>> {
+        let mut presence = self.presence.write().await;
+
+        filters
+            .iter()
+            .filter(|filter| is_tracked(filter) && presence.leave(filter, user_id))
+            .map(|filter| presence_event(filter, "Left", user_id))
+            .collect()
+    }
+
+    /// Users subscribed on this node to `topic` itself, subscribing with a
+    /// wildcard does not count as presence.
+    pub async fn presence(&self, topic: &str) -> Vec<String> {
+        self.presence.read().await.users(topic)
+    }
+
+    /// Presence events of this node's sessions, a lagging receiver misses
+    /// some of them.
+    pub fn watch_presence(&self) -> broadcast::Receiver<Event<Value, Value>> {
+        self.presence_events.subscribe()
+    }
+
+    async fn send_presence(&self, events: Vec<Event<Value, Value>>) {
+        for event in events.iter() {
+            // no receiver without other nodes
+            let _ = self.presence_events.send(event.clone());
+        }
+
+        self.publish_presence(events).await;
+    }
+
+    /// Sends presence events to the sessions of this node, like `watch_presence`
+    /// ones received from another node. They are neither retained nor kept in
+    /// history.
+    pub async fn publish_presence(&self, events: Vec<Event<Value, Value>>) {
+        if events.is_empty() {
+            return;
+        }
+
+        let events = events
+            .into_iter()
+            .map(|event| {
+                let message = Message {
+                    event,
+                    user_id: "*".to_owned(),
+                    user_ids: Vec::new(),
+                    audiences: Vec::new(),
+                    retain: false,
+                    client_id: None,
+                    exclude_client_id: None,
+                };
+
+                (self.next_id(), message)
+            })
+            .collect();
+
+        self.dispatch(events).await;
     }
 
     async fn remove_user_client(&self, user_id: &str, client_id: &str) {
@@ -241,25 +1043,161 @@ impl<T: From<String> + Clone + Debug + Sync + Send + 'static> Publisher<T> {
         }
     }
 
-    pub async fn create_client(&self, send_id: bool) -> Option<(Receiver<T>, String)> {
-        let id = nanoid!();
-        let (tx, rx) = channel::<T>(100);
-        let client = Client::new(tx);
+    /// Total of events dropped because a client buffer was full.
+    pub async fn dropped_events(&self) -> u64 {
+        let clients = self.clients.read().await;
 
-        if send_id && client.send_event_session_id(&id).is_err() {
-            return None;
+        self.dropped.load(Ordering::Relaxed) + clients.values().map(|c| c.dropped()).sum::<u64>()
+    }
+
+    fn next_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub async fn create_client(
+        &self,
+        options: ClientOptions,
+    ) -> Result<(Receiver<T>, String), Error> {
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(Error::Draining);
+        }
+
+        let suspended = match &options.resume_token {
+            Some(token) => {
+                self.suspend_closed(token).await;
+                self.take_suspended(token, options.user_id.as_deref()).await
+            }
+            None => None,
+        };
+
+        let id = match &suspended {
+            Some(session) => session.id.to_owned(),
+            None => nanoid!(),
+        };
+
+        let (tx, rx) = channel::<T>(self.buffer_size, self.overflow);
+        let mut client = Client::new(tx)
+            .resume_token(self.resume_tokens.sign(&id))
+            .last_event_id(options.last_event_id)
+            .connected_id(self.last_id.load(Ordering::Relaxed))
+            .user_id(options.user_id.clone())
+            .audiences(options.audiences);
+        let event_id = options
+            .last_event_id
+            .unwrap_or_else(|| self.last_id.load(Ordering::Relaxed));
+
+        let sent = match (&suspended, options.send_id) {
+            (_, false) => Ok(()),
+            (Some(session), true) => {
+                client.send_event_session_resumed(event_id, &id, &session.filters)
+            }
+            (None, true) => client.send_event_session_id(event_id, &id),
+        };
+
+        if sent.is_err() {
+            self.put_suspended(suspended).await;
+
+            return Err(Error::Full);
+        }
+
+        let resumed = suspended.is_some();
+
+        if let Some(session) = &suspended {
+            client = client.restore(session.clone());
         }
 
+        let user_id = client.user_id.read().await.to_owned();
+        let audiences = client.audiences.read().await.to_owned();
+        let filters = client.filters.read().await.to_owned();
+        let last_event_id = client.last_event_id;
         let mut w = self.clients.write().await;
 
+        if let Some(user_id) = &user_id {
+            if let Err(e) = self.bind_user_client(&client, user_id, &id).await {
+                // still resumable once another session of the user closed
+                self.put_suspended(suspended).await;
+
+                return Err(e);
+            }
+        }
+
+        self.index_audiences(&id, &[], &audiences).await;
+
         w.insert(id.to_owned(), client);
+        gauge!("pikav_sessions").set(w.len() as f64);
+        drop(w);
+
+        let events = match (resumed, &user_id) {
+            (true, Some(user_id)) => {
+                self.resume(&id, user_id, &audiences, &filters, last_event_id)
+                    .await
+            }
+            _ => Vec::new(),
+        };
+
+        self.send_presence(events).await;
 
-        Some((rx, id))
+        Ok((rx, id))
     }
 
-    pub async fn subscribe(
+    /// Indexes the filters of a resumed session and replays the events it
+    /// missed since its last event id.
+    async fn resume(
         &self,
-        filter: String,
+        client_id: &str,
+        user_id: &str,
+        audiences: &[String],
+        filters: &[String],
+        last_event_id: Option<u64>,
+    ) -> Vec<Event<Value, Value>> {
+        let mut events = Vec::new();
+        let mut records = Vec::new();
+
+        gauge!("pikav_subscriptions").increment(filters.len() as f64);
+
+        for filter in filters.iter() {
+            let indexed_at = self.index_filter(filter, client_id).await;
+
+            if is_tracked(filter) && self.presence.write().await.join(filter, user_id) {
+                events.push(presence_event(filter, "Joined", user_id));
+            }
+
+            if let Some(last_event_id) = last_event_id {
+                records.extend(
+                    self.history
+                        .find(filter, user_id, audiences, last_event_id)
+                        .await
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|r| r.id <= indexed_at),
+                );
+            }
+        }
+
+        records.sort_by_key(|r| r.id);
+        records.dedup_by_key(|r| r.id);
+
+        if let Some(client) = self.clients.read().await.get(client_id) {
+            client.replay(client_id, None, &records).await;
+        }
+
+        events
+    }
+
+    /// Indexes `filter` for the session and returns the last event id at that
+    /// time, events published later are sent live and must not be replayed.
+    async fn index_filter(&self, filter: &str, client_id: &str) -> u64 {
+        let mut index = self.index.write().await;
+        index.insert(filter, client_id);
+
+        self.last_id.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the audiences of the session, a user's groups can change
+    /// while its session lives.
+    pub async fn set_audiences(
+        &self,
+        audiences: Vec<String>,
         user_id: impl Into<String>,
         client_id: impl Into<String>,
     ) -> Result<(), Error> {
@@ -272,116 +1210,513 @@ impl<T: From<String> + Clone + Debug + Sync + Send + 'static> Publisher<T> {
             None => return Err(Error::SessionNotFound),
         };
 
-        if client.update_user_id(user_id.to_owned()).await {
-            self.remove_user_client(&user_id, &client_id).await;
+        if !client.is_owned_by(&user_id).await {
+            return Err(Error::UserMismatch);
+        }
+
+        let mut current = client.audiences.write().await;
+
+        if *current != audiences {
+            self.index_audiences(&client_id, &current, &audiences).await;
+            *current = audiences;
         }
 
-        if !client.insert(filter).await {
-            return Ok(());
+        Ok(())
+    }
+
+    /// Moves the session from the `removed` audiences to the `added` ones.
+    async fn index_audiences(&self, client_id: &str, removed: &[String], added: &[String]) {
+        if removed.is_empty() && added.is_empty() {
+            return;
         }
 
+        let mut audience_clients = self.audience_clients.write().await;
+
+        for audience in removed {
+            let is_empty = audience_clients
+                .get_mut(audience)
+                .map(|ids| {
+                    ids.remove(client_id);
+
+                    ids.is_empty()
+                })
+                .unwrap_or(false);
+
+            if is_empty {
+                audience_clients.remove(audience);
+            }
+        }
+
+        for audience in added {
+            audience_clients
+                .entry(audience.to_owned())
+                .or_default()
+                .insert(client_id.to_owned());
+        }
+    }
+
+    /// Binds the session to `user_id` and counts it in the sessions of that
+    /// user, unless the user already reached its quota.
+    async fn bind_user_client(
+        &self,
+        client: &Client<T>,
+        user_id: &str,
+        client_id: &str,
+    ) -> Result<(), Error> {
         let mut user_clients = self.user_clients.write().await;
+        let ids = user_clients.get(user_id);
+
+        if !ids.map(|ids| ids.contains(client_id)).unwrap_or(false) {
+            if let Some(max) = self.quotas.sessions_per_user {
+                if ids.map(|ids| ids.len()).unwrap_or(0) >= max {
+                    return Err(Error::TooManySessions(max));
+                }
+            }
+        }
+
+        client.bind_user_id(user_id).await?;
+
         user_clients
-            .entry(user_id)
-            .or_insert_with(HashSet::new)
-            .insert(client_id);
+            .entry(user_id.to_owned())
+            .or_default()
+            .insert(client_id.to_owned());
 
         Ok(())
     }
 
-    pub async fn unsubscribe(
+    pub async fn subscribe(
+        &self,
+        filter: TopicFilter,
+        user_id: impl Into<String>,
+        client_id: impl Into<String>,
+    ) -> Result<(), Error> {
+        self.subscribe_with_predicate(filter, None, user_id, client_id)
+            .await
+    }
+
+    /// Subscribes like `subscribe`, events matching `filter` are only sent when
+    /// they also satisfy `predicate`. Subscribing again to the same filter
+    /// replaces its predicate.
+    pub async fn subscribe_with_predicate(
         &self,
-        filter: String,
+        filter: TopicFilter,
+        predicate: Option<Predicate>,
         user_id: impl Into<String>,
         client_id: impl Into<String>,
     ) -> Result<(), Error> {
+        if let (Some(accepted), Some(syntax)) = (self.filter_syntax, filter.syntax()) {
+            if syntax != accepted {
+                return Err(Error::Syntax(accepted));
+            }
+        }
+
+        let filter = String::from(filter);
         let user_id = user_id.into();
         let client_id = client_id.into();
-        let clients = self.clients.read().await;
+        let mut events = Vec::new();
+        let mut replay = None;
+        let mut audiences = Vec::new();
 
-        let client = match clients.get(&client_id) {
-            Some(c) => c,
-            None => return Err(Error::SessionNotFound),
-        };
+        {
+            let clients = self.clients.read().await;
+
+            let client = match clients.get(&client_id) {
+                Some(c) => c,
+                None => return Err(Error::SessionNotFound),
+            };
+
+            if !client.is_owned_by(&user_id).await {
+                return Err(Error::UserMismatch);
+            }
+
+            // only subscribes to a session of the user count
+            if let Some(max) = self.quotas.subscribes_per_minute {
+                if !self.subscribes.write().await.hit(&user_id, max) {
+                    return Err(Error::TooManySubscribes(max));
+                }
+            }
+
+            self.bind_user_client(client, &user_id, &client_id).await?;
+
+            let inserted = client
+                .try_insert(filter.to_owned(), self.quotas.filters_per_session)
+                .await?;
+
+            client.set_predicate(&filter, predicate).await;
 
-        if !client.remove(filter).await {
-            return Ok(());
+            if inserted {
+                gauge!("pikav_subscriptions").increment(1);
+
+                let indexed_at = self.index_filter(&filter, &client_id).await;
+
+                if is_tracked(&filter) && self.presence.write().await.join(&filter, &user_id) {
+                    events.push(presence_event(&filter, "Joined", &user_id));
+                }
+
+                replay = Some((client.last_event_id, client.connected_id, indexed_at));
+                audiences = client.audiences.read().await.to_owned();
+            }
+        }
+
+        // history is read without holding the clients, a slow storage must
+        // not block publishing
+        if let Some((last_event_id, connected_id, indexed_at)) = replay {
+            // `Last-Event-ID` tells what was missed while disconnected, events
+            // published since the connection are not replayed to new filters
+            let mut records = match last_event_id {
+                Some(last_event_id) => self
+                    .history
+                    .find(&filter, &user_id, &audiences, last_event_id)
+                    .await
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|r| r.id <= connected_id.min(indexed_at))
+                    .collect(),
+                None => Vec::new(),
+            };
+
+            {
+                let ids = records.iter().map(|r| r.id).collect::<HashSet<_>>();
+                let retained = self.retained.read().await;
+
+                records.extend(
+                    retained
+                        .values()
+                        .filter(|r| {
+                            r.id <= indexed_at
+                                && r.is_for(&user_id, &audiences)
+                                && !ids.contains(&r.id)
+                                && filter_match(&filter, r.topic())
+                        })
+                        .cloned(),
+                );
+            }
+
+            records.sort_by_key(|r| r.id);
+
+            if let Some(client) = self.clients.read().await.get(&client_id) {
+                client.replay(&client_id, Some(&filter), &records).await;
+            }
         }
 
-        self.remove_user_client(&user_id, &client_id).await;
+        self.send_presence(events).await;
 
         Ok(())
     }
 
-    pub async fn publish(&self, events: Vec<Message<SimpleEvent>>) {
-        let user_clients = self.user_clients.read().await;
-        let clients = self.clients.read().await;
-        let mut futures = Vec::new();
+    pub async fn unsubscribe(
+        &self,
+        filter: TopicFilter,
+        user_id: impl Into<String>,
+        client_id: impl Into<String>,
+    ) -> Result<(), Error> {
+        let filter = String::from(filter);
+        let user_id = user_id.into();
+        let client_id = client_id.into();
 
-        for event in events {
-            if &event.user_id == "*" {
-                for (_, client) in clients.iter() {
-                    futures.push(client.filter_send(event.event.clone()));
-                }
+        let events = {
+            let clients = self.clients.read().await;
 
-                continue;
+            let client = match clients.get(&client_id) {
+                Some(c) => c,
+                None => return Err(Error::SessionNotFound),
+            };
+
+            if !client.is_owned_by(&user_id).await {
+                return Err(Error::UserMismatch);
             }
 
-            let ids = match user_clients.get(&event.user_id) {
-                Some(clients) => clients,
-                None => continue,
-            };
+            let subscribed = client.filters.read().await.contains(&filter);
+
+            self.index.write().await.remove(&filter, &client_id);
+
+            // the session stays bound to the user, and counted in its sessions,
+            // until it is removed
+            client.remove(filter.to_owned()).await;
+
+            match subscribed {
+                true => {
+                    gauge!("pikav_subscriptions").decrement(1);
 
-            for id in ids {
-                if let Some(client) = clients.get(id) {
-                    futures.push(client.filter_send(event.event.clone()));
+                    self.leave(&user_id, &[filter]).await
                 }
+                false => Vec::new(),
             }
-        }
+        };
+
+        self.send_presence(events).await;
 
-        let stream = futures::stream::iter(futures).buffer_unordered(50);
-        let _ = stream.collect::<Vec<_>>().await;
+        Ok(())
+    }
+
+    pub async fn publish(&self, events: Vec<Message<SimpleEvent>>) {
+        let events = events
+            .into_iter()
+            .map(|event| {
+                counter!("pikav_events_published_total", "namespace" => namespace(&event.event.topic))
+                    .increment(1);
+
+                (self.next_id(), event)
+            })
+            .collect::<Vec<_>>();
+
+        self.retain(
+            events
+                .iter()
+                .filter(|(_, event)| event.retain && event.client_id.is_none())
+                .map(|(id, event)| record(*id, event, Payload::Simple(event.event.clone())))
+                .collect(),
+        )
+        .await;
+
+        self.history
+            .push(
+                events
+                    .iter()
+                    .filter(|(_, event)| event.client_id.is_none())
+                    .map(|(id, event)| record(*id, event, Payload::Simple(event.event.clone())))
+                    .collect(),
+            )
+            .await
+            .ok();
+
+        let clients = self.clients.read().await;
+        let user_clients = self.user_clients.read().await;
+        let audience_clients = self.audience_clients.read().await;
+        let index = self.index.read().await;
+
+        for (id, event) in events {
+            let mut recipients = Vec::new();
+
+            index
+                .matches(&event.event.topic)
+                .for_each(|client_id, filters| {
+                    if !is_recipient(&user_clients, &audience_clients, &event, client_id) {
+                        return;
+                    }
+
+                    if let Some(client) = clients.get(client_id) {
+                        recipients.push((client, filters.to_vec()));
+                    }
+                });
+
+            let value = OnceLock::new();
+            let data = Bytes::from(event.event.data);
+            let mut delivery = Delivery::default();
+
+            for (client, filters) in recipients {
+                let filters = client
+                    .accepted(&filters, &event.event.event, || {
+                        value.get_or_init(|| simple_data(&String::from_utf8_lossy(&data)))
+                    })
+                    .await;
+
+                if !filters.is_empty() {
+                    delivery.send(
+                        client,
+                        &event.event.topic,
+                        Frame::new(id, &event.event.event, data.clone()),
+                    );
+                }
+            }
+
+            delivery.count(&event.event.topic);
+        }
     }
 
     pub async fn publish_events<D: Serialize + Clone, M: Serialize + Clone>(
         &self,
         events: Vec<Message<Event<D, M>>>,
     ) {
-        let user_clients = self.user_clients.read().await;
-        let clients = self.clients.read().await;
-        let mut futures = Vec::new();
-
-        for event in events {
-            if &event.user_id == "*" {
-                for (_, client) in clients.iter() {
-                    futures.push(client.filter_send_event(event.event.clone()));
+        let mut retained = Vec::new();
+        let events = events
+            .into_iter()
+            .map(|message| {
+                let event = Event::<Value, Value> {
+                    topic: message.event.topic,
+                    name: message.event.name,
+                    data: serde_json::to_value(&message.event.data).unwrap_or_default(),
+                    metadata: message
+                        .event
+                        .metadata
+                        .and_then(|m| serde_json::to_value(m).ok()),
+                    filters: None,
+                };
+
+                let id = self.next_id();
+
+                counter!("pikav_events_published_total", "namespace" => namespace(&event.topic))
+                    .increment(1);
+
+                let message = Message {
+                    event,
+                    user_id: message.user_id,
+                    user_ids: message.user_ids,
+                    audiences: message.audiences,
+                    retain: message.retain,
+                    client_id: message.client_id,
+                    exclude_client_id: message.exclude_client_id,
+                };
+
+                if message.retain && message.client_id.is_none() {
+                    retained.push(record(id, &message, Payload::Event(message.event.clone())));
                 }
 
-                continue;
+                (id, message)
+            })
+            .collect::<Vec<_>>();
+
+        self.retain(retained).await;
+
+        self.history
+            .push(
+                events
+                    .iter()
+                    .filter(|(_, message)| message.client_id.is_none())
+                    .map(|(id, message)| {
+                        record(*id, message, Payload::Event(message.event.clone()))
+                    })
+                    .collect(),
+            )
+            .await
+            .ok();
+
+        self.dispatch(events).await;
+    }
+
+    async fn retain(&self, records: Vec<Record>) {
+        if records.is_empty() {
+            return;
+        }
+
+        let mut retained = self.retained.write().await;
+
+        for record in records {
+            let key = record.retain_key();
+
+            if record.payload.is_empty() {
+                retained.remove(&key);
+            } else {
+                retained.insert(key, record);
             }
+        }
+    }
 
-            let ids = match user_clients.get(&event.user_id) {
-                Some(clients) => clients,
-                None => continue,
-            };
+    async fn dispatch(&self, events: Vec<(u64, Message<Event<Value, Value>>)>) {
+        let clients = self.clients.read().await;
+        let user_clients = self.user_clients.read().await;
+        let audience_clients = self.audience_clients.read().await;
+        let index = self.index.read().await;
+
+        for (id, message) in events {
+            let event = &message.event;
+            let payload = Bytes::from(serde_json::to_vec(event).unwrap());
+            let mut recipients = Vec::new();
+            let mut delivery = Delivery::default();
+
+            index.matches(&event.topic).for_each(|client_id, filters| {
+                if !is_recipient(&user_clients, &audience_clients, &message, client_id) {
+                    return;
+                }
 
-            for id in ids {
-                if let Some(client) = clients.get(id) {
-                    futures.push(client.filter_send_event(event.event.clone()));
+                if let Some(client) = clients.get(client_id) {
+                    recipients.push((client, filters.to_vec()));
+                }
+            });
+
+            for (client, filters) in recipients {
+                let filters = client.accepted(&filters, &event.name, || &event.data).await;
+
+                if !filters.is_empty() {
+                    delivery.send(client, &event.topic, Frame::message(id, &payload, &filters));
                 }
             }
+
+            delivery.count(&event.topic);
         }
+    }
+}
+
+/// Only subscriptions to a topic, without wildcard, count as presence so
+/// that `$SYS/presence/{topic}` events and `presence(topic)` agree. `$SYS`
+/// subscriptions, presence ones included, do not count.
+fn is_tracked(filter: &str) -> bool {
+    !filter.starts_with("$SYS/") && syntax_of(filter).is_none()
+}
+
+/// Whether `topic` is the one of a presence event, see `presence_event`.
+pub fn is_presence(topic: &str) -> bool {
+    topic.starts_with("$SYS/presence/")
+}
+
+fn presence_event(topic: &str, name: &str, user_id: &str) -> Event<Value, Value> {
+    Event::with_metadata(
+        format!("$SYS/presence/{topic}"),
+        name,
+        Value::String(user_id.to_owned()),
+    )
+}
+
+fn jitter(max: Duration) -> Duration {
+    match max.as_millis() as u64 {
+        0 => Duration::ZERO,
+        max => Duration::from_millis(RandomState::new().build_hasher().finish() % (max + 1)),
+    }
+}
+
+/// First segment of a topic, metrics are labelled with it.
+fn namespace(topic: &str) -> String {
+    topic.split('/').next().unwrap_or_default().to_owned()
+}
+
+/// Data of a `SimpleEvent` as seen by predicates, a string when it is not JSON.
+fn simple_data(data: &str) -> Value {
+    serde_json::from_str(data).unwrap_or_else(|_| Value::String(data.to_owned()))
+}
+
+/// History record of the event with the recipients of `message`.
+fn record<E>(id: u64, message: &Message<E>, payload: Payload) -> Record {
+    Record::new(id, &message.user_id, payload)
+        .recipients(message.user_ids.to_owned(), message.audiences.to_owned())
+        .exclude_client_id(message.exclude_client_id.to_owned())
+}
 
-        let stream = futures::stream::iter(futures).buffer_unordered(50);
-        let _ = stream.collect::<Vec<_>>().await;
+fn is_recipient<E>(
+    user_clients: &HashMap<String, HashSet<String>>,
+    audience_clients: &HashMap<String, HashSet<String>>,
+    message: &Message<E>,
+    client_id: &str,
+) -> bool {
+    if message
+        .client_id
+        .as_deref()
+        .is_some_and(|target| target != client_id)
+        || message.exclude_client_id.as_deref() == Some(client_id)
+    {
+        return false;
     }
+
+    let is_member = |members: &HashMap<String, HashSet<String>>, key: &String| {
+        members
+            .get(key)
+            .map(|ids| ids.contains(client_id))
+            .unwrap_or(false)
+    };
+
+    message.user_id == "*"
+        || is_member(user_clients, &message.user_id)
+        || message
+            .user_ids
+            .iter()
+            .any(|id| is_member(user_clients, id))
+        || message
+            .audiences
+            .iter()
+            .any(|audience| is_member(audience_clients, audience))
 }
 
-impl<T: From<String> + Clone + Debug + Sync + Send + 'static> Default for Publisher<T> {
+impl<T: From<Frame> + Clone + Debug + Sync + Send + 'static> Default for Publisher<T> {
     fn default() -> Self {
-        Self {
-            clients: Arc::default(),
-            user_clients: Arc::default(),
-        }
+        Self::new(PublisherOptions::default())
     }
 }
@@ -0,0 +1,366 @@
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll, Waker},
+};
+use tokio::sync::mpsc::error::TrySendError;
+
+/// What to do with a new event when a client buffer is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Drop the new event.
+    #[default]
+    DropNewest,
+    /// Drop the oldest buffered event to make room for the new one.
+    DropOldest,
+    /// Close the client stream.
+    Disconnect,
+    /// Replace the buffered event of the same topic, drop the new event if there is none.
+    Coalesce,
+}
+
+struct State<T> {
+    queue: VecDeque<(Option<String>, T)>,
+    notice: Option<T>,
+    waker: Option<Waker>,
+    tx_closed: bool,
+    rx_closed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    overflow: Overflow,
+    dropped: AtomicU64,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn channel<T>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            notice: None,
+            waker: None,
+            tx_closed: false,
+            rx_closed: false,
+        }),
+        capacity: capacity.max(1),
+        overflow,
+        dropped: AtomicU64::new(0),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.push(None, value, || None)
+    }
+
+    /// Sends `value` applying the overflow policy when the buffer is full,
+    /// `notice` is delivered ahead of the buffer to tell about dropped events.
+    pub fn try_send_topic(
+        &self,
+        topic: &str,
+        value: T,
+        notice: impl FnOnce(u64) -> T,
+    ) -> Result<(), TrySendError<T>> {
+        let key = (self.shared.overflow == Overflow::Coalesce).then(|| topic.to_owned());
+
+        self.push(key, value, || {
+            let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+
+            Some(notice(dropped))
+        })
+    }
+
+    fn push(
+        &self,
+        key: Option<String>,
+        value: T,
+        on_drop: impl FnOnce() -> Option<T>,
+    ) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.lock();
+
+        if state.rx_closed || state.tx_closed {
+            return Err(TrySendError::Closed(value));
+        }
+
+        if state.queue.len() < self.shared.capacity {
+            state.queue.push_back((key, value));
+            self.wake(state);
+
+            return Ok(());
+        }
+
+        let notice = on_drop();
+
+        let res = match (notice.is_some(), self.shared.overflow) {
+            (false, _) | (true, Overflow::DropNewest) => Err(TrySendError::Full(value)),
+            (true, Overflow::DropOldest) => {
+                state.queue.pop_front();
+                state.queue.push_back((key, value));
+
+                Ok(())
+            }
+            (true, Overflow::Disconnect) => {
+                state.queue.clear();
+                state.tx_closed = true;
+
+                Err(TrySendError::Closed(value))
+            }
+            (true, Overflow::Coalesce) => {
+                // the buffered event moves to the back so ids stay in order
+                match state
+                    .queue
+                    .iter()
+                    .position(|(k, _)| k.is_some() && k == &key)
+                {
+                    Some(pos) => {
+                        state.queue.remove(pos);
+                        state.queue.push_back((key, value));
+
+                        Ok(())
+                    }
+                    None => Err(TrySendError::Full(value)),
+                }
+            }
+        };
+
+        if notice.is_some() {
+            state.notice = notice;
+        }
+
+        self.wake(state);
+
+        res
+    }
+
+    fn wake(&self, mut state: MutexGuard<'_, State<T>>) {
+        let waker = state.waker.take();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let state = self.shared.lock();
+
+        state.rx_closed || state.tx_closed
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.tx_closed = true;
+
+        self.wake(state);
+    }
+}
+
+impl<T> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender")
+            .field("capacity", &self.shared.capacity)
+            .field("overflow", &self.shared.overflow)
+            .finish()
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.shared.lock();
+
+        if let Some(notice) = state.notice.take() {
+            return Poll::Ready(Some(notice));
+        }
+
+        if let Some((_, value)) = state.queue.pop_front() {
+            return Poll::Ready(Some(value));
+        }
+
+        if state.tx_closed {
+            return Poll::Ready(None);
+        }
+
+        state.waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let mut state = self.shared.lock();
+
+        state
+            .notice
+            .take()
+            .or_else(|| state.queue.pop_front().map(|(_, value)| value))
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.rx_closed = true;
+        state.queue.clear();
+    }
+}
+
+impl<T> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Receiver").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(dropped: u64) -> String {
+        format!("dropped {dropped}")
+    }
+
+    fn fill(tx: &Sender<String>) {
+        tx.try_send_topic("a", "1".to_owned(), notice).unwrap();
+        tx.try_send_topic("b", "2".to_owned(), notice).unwrap();
+    }
+
+    fn drain(rx: &mut Receiver<String>) -> Vec<String> {
+        std::iter::from_fn(|| rx.try_recv()).collect()
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (tx, mut rx) = channel(2, Overflow::DropNewest);
+        fill(&tx);
+
+        assert!(matches!(
+            tx.try_send_topic("c", "3".to_owned(), notice),
+            Err(TrySendError::Full(_))
+        ));
+        assert_eq!(tx.dropped(), 1);
+        assert_eq!(drain(&mut rx), ["dropped 1", "1", "2"]);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (tx, mut rx) = channel(2, Overflow::DropOldest);
+        fill(&tx);
+
+        tx.try_send_topic("c", "3".to_owned(), notice).unwrap();
+        tx.try_send_topic("d", "4".to_owned(), notice).unwrap();
+
+        assert_eq!(tx.dropped(), 2);
+        assert_eq!(drain(&mut rx), ["dropped 2", "3", "4"]);
+    }
+
+    #[test]
+    fn disconnect() {
+        let (tx, mut rx) = channel(2, Overflow::Disconnect);
+        fill(&tx);
+
+        assert!(matches!(
+            tx.try_send_topic("c", "3".to_owned(), notice),
+            Err(TrySendError::Closed(_))
+        ));
+        assert!(tx.is_closed());
+        assert_eq!(drain(&mut rx), ["dropped 1"]);
+        assert!(matches!(
+            tx.try_send_topic("d", "4".to_owned(), notice),
+            Err(TrySendError::Closed(_))
+        ));
+    }
+
+    #[test]
+    fn coalesce() {
+        let (tx, mut rx) = channel(2, Overflow::Coalesce);
+        fill(&tx);
+
+        tx.try_send_topic("a", "3".to_owned(), notice).unwrap();
+
+        assert!(matches!(
+            tx.try_send_topic("c", "4".to_owned(), notice),
+            Err(TrySendError::Full(_))
+        ));
+        assert_eq!(tx.dropped(), 2);
+        assert_eq!(drain(&mut rx), ["dropped 2", "2", "3"]);
+    }
+
+    #[test]
+    fn try_send_never_drops() {
+        let (tx, mut rx) = channel(1, Overflow::DropOldest);
+        tx.try_send("1".to_owned()).unwrap();
+
+        assert!(matches!(
+            tx.try_send("2".to_owned()),
+            Err(TrySendError::Full(_))
+        ));
+        assert_eq!(tx.dropped(), 0);
+        assert_eq!(drain(&mut rx), ["1"]);
+    }
+
+    #[tokio::test]
+    async fn recv_ends_once_sender_dropped() {
+        let (tx, mut rx) = channel(2, Overflow::DropNewest);
+        tx.try_send("1".to_owned()).unwrap();
+        drop(tx);
+
+        assert_eq!(rx.recv().await.as_deref(), Some("1"));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[test]
+    fn receiver_dropped_closes() {
+        let (tx, rx) = channel::<String>(2, Overflow::DropNewest);
+        drop(rx);
+
+        assert!(tx.is_closed());
+        assert!(matches!(
+            tx.try_send("1".to_owned()),
+            Err(TrySendError::Closed(_))
+        ));
+    }
+}
@@ -0,0 +1,99 @@
+use bytes::{Bytes, BytesMut};
+
+/// A server-sent event split in parts so that the payload shared by every
+/// recipient is never copied.
+#[derive(Debug, Clone, Default)]
+pub struct Frame {
+    parts: Vec<Bytes>,
+}
+
+impl Frame {
+    pub fn new(id: u64, event: &str, data: Bytes) -> Self {
+        Self {
+            parts: vec![
+                format!("id: {id}\nevent: {event}\ndata: ").into(),
+                data,
+                Bytes::from_static(b"\n\n"),
+            ],
+        }
+    }
+
+    pub fn without_id(event: &str, data: Bytes) -> Self {
+        Self {
+            parts: vec![
+                format!("event: {event}\ndata: ").into(),
+                data,
+                Bytes::from_static(b"\n\n"),
+            ],
+        }
+    }
+
+    /// `payload` is a serialized JSON object, the client filters are inserted
+    /// as its first field.
+    pub fn message(id: u64, payload: &Bytes, filters: &[&str]) -> Self {
+        let filters = serde_json::to_string(filters).unwrap_or_else(|_| "[]".to_owned());
+
+        Self {
+            parts: vec![
+                format!("id: {id}\nevent: message\ndata: {{\"filters\":{filters},").into(),
+                payload.slice(1..),
+                Bytes::from_static(b"\n\n"),
+            ],
+        }
+    }
+
+    /// Sets the reconnection time of `EventSource`, in milliseconds.
+    pub fn retry(mut self, value: u64) -> Self {
+        self.parts.insert(0, format!("retry: {value}\n").into());
+
+        self
+    }
+
+    pub fn from_static(value: &'static [u8]) -> Self {
+        Self {
+            parts: vec![Bytes::from_static(value)],
+        }
+    }
+
+    pub fn parts(&self) -> &[Bytes] {
+        &self.parts
+    }
+
+    pub fn into_parts(self) -> Vec<Bytes> {
+        self.parts
+    }
+
+    pub fn len(&self) -> usize {
+        self.parts.iter().map(|p| p.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<String> for Frame {
+    fn from(value: String) -> Self {
+        Self {
+            parts: vec![value.into()],
+        }
+    }
+}
+
+impl From<Frame> for Bytes {
+    fn from(value: Frame) -> Self {
+        let mut bytes = BytesMut::with_capacity(value.len());
+
+        for part in value.parts {
+            bytes.extend_from_slice(&part);
+        }
+
+        bytes.freeze()
+    }
+}
+
+impl From<Frame> for String {
+    fn from(value: Frame) -> Self {
+        String::from_utf8_lossy(&Bytes::from(value)).into_owned()
+    }
+}
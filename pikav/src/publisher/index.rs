@@ -0,0 +1,342 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::topic::filter_match;
+
+#[derive(Debug, Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    wildcard: Option<Box<Node>>,
+    globstar: Option<Box<Node>>,
+    /// MQTT `+`, kept apart from `*` to report the filter as subscribed.
+    single: Option<Box<Node>>,
+    /// MQTT `#`, always the last segment.
+    multi: Option<Box<Node>>,
+    clients: HashSet<String>,
+    filter: Option<String>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+            && self.literals.is_empty()
+            && self.wildcard.is_none()
+            && self.globstar.is_none()
+            && self.single.is_none()
+            && self.multi.is_none()
+    }
+
+    fn child(&mut self, segment: &str) -> &mut Node {
+        match segment {
+            "*" => self.wildcard.get_or_insert_with(Box::default),
+            "**" => self.globstar.get_or_insert_with(Box::default),
+            "+" => self.single.get_or_insert_with(Box::default),
+            "#" => self.multi.get_or_insert_with(Box::default),
+            _ => self.literals.entry(segment.to_owned()).or_default(),
+        }
+    }
+
+    fn remove(&mut self, segments: &[&str], client_id: &str) -> bool {
+        let Some((segment, rest)) = segments.split_first() else {
+            return self.clients.remove(client_id);
+        };
+
+        let child = match *segment {
+            "*" => self.wildcard.as_deref_mut(),
+            "**" => self.globstar.as_deref_mut(),
+            "+" => self.single.as_deref_mut(),
+            "#" => self.multi.as_deref_mut(),
+            _ => self.literals.get_mut(*segment),
+        };
+
+        let Some(child) = child else {
+            return false;
+        };
+
+        let removed = child.remove(rest, client_id);
+
+        if child.is_empty() {
+            match *segment {
+                "*" => self.wildcard = None,
+                "**" => self.globstar = None,
+                "+" => self.single = None,
+                "#" => self.multi = None,
+                _ => {
+                    self.literals.remove(*segment);
+                }
+            }
+        }
+
+        removed
+    }
+
+    fn push<'a>(&'a self, groups: &mut Vec<(&'a str, &'a HashSet<String>)>) {
+        if let (Some(filter), false) = (&self.filter, self.clients.is_empty()) {
+            groups.push((filter, &self.clients));
+        }
+    }
+
+    fn collect<'a>(&'a self, segments: &[&str], groups: &mut Vec<(&'a str, &'a HashSet<String>)>) {
+        // `#` matches the remaining segments, none included: `a/#` matches `a`
+        if let Some(node) = &self.multi {
+            node.push(groups);
+        }
+
+        let Some((segment, rest)) = segments.split_first() else {
+            self.push(groups);
+
+            return;
+        };
+
+        if let Some(node) = self.literals.get(*segment) {
+            node.collect(rest, groups);
+        }
+
+        if let Some(node) = &self.wildcard {
+            node.collect(rest, groups);
+        }
+
+        if let Some(node) = &self.single {
+            node.collect(rest, groups);
+        }
+
+        if let Some(node) = &self.globstar {
+            // `**` consumes zero or more segments, a filter only ends on an empty rest so a
+            // trailing `**` still needs at least one segment like `glob_match` does
+            for i in 0..=segments.len() {
+                node.collect(&segments[i..], groups);
+            }
+        }
+    }
+}
+
+/// Resolves which clients have a filter matching a topic without testing every filter of
+/// every client. Filters made of literal, `*`, `**`, `+` and `#` segments live in a segment
+/// trie, any other glob falls back to `glob_match`.
+#[derive(Debug, Default)]
+pub struct SubscriptionIndex {
+    root: Node,
+    patterns: HashMap<String, HashSet<String>>,
+}
+
+impl SubscriptionIndex {
+    fn is_indexable(filter: &str) -> bool {
+        let mut globstar = false;
+
+        filter.split('/').all(|segment| {
+            // glob_match backtracks differently once a wildcard or an empty segment follows `**`
+            if globstar && matches!(segment, "" | "*" | "**") {
+                return false;
+            }
+
+            globstar |= segment == "**";
+
+            matches!(segment, "*" | "**")
+                || !segment.contains(['*', '?', '[', ']', '{', '}', '\\', '!'])
+        })
+    }
+
+    pub fn insert(&mut self, filter: &str, client_id: impl Into<String>) -> bool {
+        if !Self::is_indexable(filter) {
+            return self
+                .patterns
+                .entry(filter.to_owned())
+                .or_default()
+                .insert(client_id.into());
+        }
+
+        let mut node = &mut self.root;
+
+        for segment in filter.split('/') {
+            node = node.child(segment);
+        }
+
+        node.filter.get_or_insert_with(|| filter.to_owned());
+        node.clients.insert(client_id.into())
+    }
+
+    pub fn remove(&mut self, filter: &str, client_id: &str) -> bool {
+        if !Self::is_indexable(filter) {
+            let Some(ids) = self.patterns.get_mut(filter) else {
+                return false;
+            };
+
+            let removed = ids.remove(client_id);
+
+            if ids.is_empty() {
+                self.patterns.remove(filter);
+            }
+
+            return removed;
+        }
+
+        self.root
+            .remove(&filter.split('/').collect::<Vec<_>>(), client_id)
+    }
+
+    pub fn matches(&self, topic: &str) -> Matches<'_> {
+        let mut groups = Vec::new();
+        let segments = topic.split('/').collect::<Vec<_>>();
+
+        // a topic starting with `$`, e.g. `$SYS/presence/todos`, is only matched by filters
+        // starting with the same segment
+        match segments.split_first() {
+            Some((first, rest)) if first.starts_with('$') => {
+                if let Some(node) = self.root.literals.get(*first) {
+                    node.collect(rest, &mut groups);
+                }
+            }
+            _ => self.root.collect(&segments, &mut groups),
+        }
+
+        for (filter, ids) in self.patterns.iter() {
+            if filter_match(filter, topic) {
+                groups.push((filter, ids));
+            }
+        }
+
+        groups.sort_unstable_by_key(|(filter, _)| *filter);
+        groups.dedup_by_key(|(filter, _)| *filter);
+
+        Matches { groups }
+    }
+}
+
+/// Clients matching a topic, grouped by the filter they subscribed with.
+pub struct Matches<'a> {
+    groups: Vec<(&'a str, &'a HashSet<String>)>,
+}
+
+impl<'a> Matches<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Calls `f` once per client with all of its filters matching the topic.
+    pub fn for_each(&self, mut f: impl FnMut(&'a str, &[&'a str])) {
+        match self.groups.as_slice() {
+            [] => {}
+            [(filter, ids)] => {
+                for id in ids.iter() {
+                    f(id, std::slice::from_ref(filter));
+                }
+            }
+            groups => {
+                let mut clients: HashMap<&str, Vec<&str>> = HashMap::new();
+
+                for (filter, ids) in groups {
+                    for id in ids.iter() {
+                        clients.entry(id).or_default().push(filter);
+                    }
+                }
+
+                for (id, filters) in clients {
+                    f(id, &filters);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [&str; 16] = [
+        "a",
+        "a/b",
+        "a/*",
+        "a/**",
+        "a/**/c",
+        "**/c",
+        "*/b",
+        "a/+",
+        "a/#",
+        "+/b",
+        "+/+/c",
+        "a/+/#",
+        "$SYS/#",
+        "$SYS/presence/+",
+        "$SYS/**",
+        "a/{b,c}",
+    ];
+
+    const TOPICS: [&str; 9] = [
+        "a",
+        "a/b",
+        "a/c",
+        "a/b/c",
+        "x/b",
+        "x/y/c",
+        "$SYS/presence/a",
+        "$SYS",
+        "SYS/presence/a",
+    ];
+
+    fn matched(index: &SubscriptionIndex, topic: &str) -> Vec<String> {
+        let mut filters = Vec::new();
+
+        index.matches(topic).for_each(|_, matched| {
+            filters.extend(matched.iter().map(|f| f.to_string()));
+        });
+
+        filters.sort();
+        filters
+    }
+
+    #[test]
+    fn agrees_with_filter_match() {
+        let mut index = SubscriptionIndex::default();
+
+        for filter in FILTERS {
+            index.insert(filter, "c1");
+        }
+
+        for topic in TOPICS {
+            let mut expected = FILTERS
+                .iter()
+                .filter(|filter| filter_match(filter, topic))
+                .map(|filter| filter.to_string())
+                .collect::<Vec<_>>();
+
+            expected.sort();
+
+            assert_eq!(matched(&index, topic), expected, "{topic}");
+        }
+    }
+
+    #[test]
+    fn multi_level_matches_parent() {
+        let mut index = SubscriptionIndex::default();
+        index.insert("a/#", "c1");
+        index.insert("a/**", "c2");
+
+        assert_eq!(matched(&index, "a"), ["a/#"]);
+        assert_eq!(matched(&index, "a/b/c"), ["a/#", "a/**"]);
+    }
+
+    #[test]
+    fn sys_topics_need_sys_filters() {
+        let mut index = SubscriptionIndex::default();
+        index.insert("+/presence/a", "c1");
+        index.insert("**/a", "c1");
+        index.insert("$SYS/presence/+", "c2");
+
+        assert_eq!(matched(&index, "$SYS/presence/a"), ["$SYS/presence/+"]);
+        assert_eq!(matched(&index, "SYS/presence/a"), ["**/a", "+/presence/a"]);
+    }
+
+    #[test]
+    fn remove() {
+        let mut index = SubscriptionIndex::default();
+        index.insert("a/+", "c1");
+        index.insert("a/#", "c1");
+
+        assert!(index.remove("a/+", "c1"));
+        assert!(!index.remove("a/+", "c1"));
+        assert_eq!(matched(&index, "a/b"), ["a/#"]);
+
+        assert!(index.remove("a/#", "c1"));
+        assert!(index.matches("a/b").is_empty());
+        assert!(index.root.is_empty());
+    }
+}
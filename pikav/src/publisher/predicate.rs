@@ -0,0 +1,335 @@
+use serde_json::{Number, Value};
+use std::{fmt, iter::Peekable, str::FromStr, vec::IntoIter};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Name,
+    Data(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    In,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    field: Field,
+    op: Op,
+    values: Vec<Value>,
+}
+
+impl Condition {
+    fn matches(&self, name: &str, data: &Value) -> bool {
+        let found = self.values.iter().any(|value| match &self.field {
+            Field::Name => value.as_str() == Some(name),
+            Field::Data(path) => is_eq(lookup(data, path).unwrap_or(&Value::Null), value),
+        });
+
+        match self.op {
+            Op::Eq | Op::In => found,
+            Op::Ne => !found,
+        }
+    }
+
+    fn parse(tokens: &mut Peekable<IntoIter<Token>>) -> Result<Self, ParseError> {
+        let field = match tokens.next() {
+            Some(Token::Word(word)) if word == "name" => Field::Name,
+            Some(Token::Word(word)) if word == "data" => Field::Data(Vec::new()),
+            Some(Token::Word(word)) if word.starts_with("data.") => {
+                Field::Data(word[5..].split('.').map(str::to_owned).collect())
+            }
+            Some(Token::Word(word)) => return Err(ParseError(format!("unknown field `{word}`"))),
+            token => return Err(unexpected(token)),
+        };
+
+        let op = match tokens.next() {
+            Some(Token::Eq) => Op::Eq,
+            Some(Token::Ne) => Op::Ne,
+            Some(Token::Word(word)) if word == "in" => Op::In,
+            token => return Err(unexpected(token)),
+        };
+
+        let values = match op {
+            Op::Eq | Op::Ne => vec![parse_value(tokens.next())?],
+            Op::In => {
+                match tokens.next() {
+                    Some(Token::Open) => {}
+                    token => return Err(unexpected(token)),
+                }
+
+                let mut values = Vec::new();
+
+                if tokens.next_if_eq(&Token::Close).is_none() {
+                    loop {
+                        values.push(parse_value(tokens.next())?);
+
+                        match tokens.next() {
+                            Some(Token::Comma) => {}
+                            Some(Token::Close) => break,
+                            token => return Err(unexpected(token)),
+                        }
+                    }
+                }
+
+                values
+            }
+        };
+
+        Ok(Self { field, op, values })
+    }
+}
+
+/// Condition on the name and on the `data` fields of an event, checked before
+/// sending it to a subscription, e.g.
+/// `name in [Created, Updated] && data.status == "open"`.
+///
+/// `&&` binds tighter than `||`, a missing field equals `null` and a bare word
+/// which is not `true`, `false`, `null` or a number is a string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate(Vec<Vec<Condition>>);
+
+impl Predicate {
+    pub fn matches(&self, name: &str, data: &Value) -> bool {
+        self.0
+            .iter()
+            .any(|all| all.iter().all(|condition| condition.matches(name, data)))
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = tokenize(s)?.into_iter().peekable();
+        let mut any = Vec::new();
+        let mut all = Vec::new();
+
+        loop {
+            all.push(Condition::parse(&mut tokens)?);
+
+            match tokens.next() {
+                None => break,
+                Some(Token::And) => {}
+                Some(Token::Or) => any.push(std::mem::take(&mut all)),
+                token => return Err(unexpected(token)),
+            }
+        }
+
+        any.push(all);
+
+        Ok(Self(any))
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid predicate: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Eq,
+    Ne,
+    And,
+    Or,
+    Open,
+    Close,
+    Comma,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "`{word}`"),
+            Token::Str(value) => write!(f, "{value:?}"),
+            Token::Eq => f.write_str("`==`"),
+            Token::Ne => f.write_str("`!=`"),
+            Token::And => f.write_str("`&&`"),
+            Token::Or => f.write_str("`||`"),
+            Token::Open => f.write_str("`[`"),
+            Token::Close => f.write_str("`]`"),
+            Token::Comma => f.write_str("`,`"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '[' => Token::Open,
+            ']' => Token::Close,
+            ',' => Token::Comma,
+            '=' | '!' | '&' | '|' => match (c, chars.next()) {
+                ('=', Some('=')) => Token::Eq,
+                ('!', Some('=')) => Token::Ne,
+                ('&', Some('&')) => Token::And,
+                ('|', Some('|')) => Token::Or,
+                _ => return Err(ParseError(format!("unexpected `{c}`"))),
+            },
+            '"' => {
+                let mut value = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => value.push(c),
+                            None => return Err(ParseError("unterminated string".to_owned())),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err(ParseError("unterminated string".to_owned())),
+                    }
+                }
+
+                Token::Str(value)
+            }
+            c => {
+                let mut word = String::from(c);
+
+                while let Some(c) = chars.next_if(|c| !is_delimiter(*c)) {
+                    word.push(c);
+                }
+
+                Token::Word(word)
+            }
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '[' | ']' | ',' | '=' | '!' | '&' | '|' | '"')
+}
+
+fn parse_value(token: Option<Token>) -> Result<Value, ParseError> {
+    match token {
+        Some(Token::Str(value)) => Ok(Value::String(value)),
+        Some(Token::Word(word)) => Ok(match word.as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            "null" => Value::Null,
+            _ => match word.parse::<Number>() {
+                Ok(number) => Value::Number(number),
+                _ => Value::String(word),
+            },
+        }),
+        token => Err(unexpected(token)),
+    }
+}
+
+fn unexpected(token: Option<Token>) -> ParseError {
+    match token {
+        Some(token) => ParseError(format!("unexpected {token}")),
+        None => ParseError("unexpected end".to_owned()),
+    }
+}
+
+fn lookup<'a>(data: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(data, |data, key| match data {
+        Value::Array(values) => key.parse::<usize>().ok().and_then(|i| values.get(i)),
+        _ => data.get(key),
+    })
+}
+
+/// `1` and `1.0` are the same number.
+fn is_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(s: &str) -> Predicate {
+        s.parse().unwrap()
+    }
+
+    fn error(s: &str) -> String {
+        s.parse::<Predicate>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let predicate = parse("name == A || name == B && data.ok == true");
+
+        assert_eq!(predicate.0.len(), 2);
+        assert_eq!(predicate.0[0].len(), 1);
+        assert_eq!(predicate.0[1].len(), 2);
+
+        assert!(predicate.matches("A", &json!({ "ok": false })));
+        assert!(predicate.matches("B", &json!({ "ok": true })));
+        assert!(!predicate.matches("B", &json!({ "ok": false })));
+
+        let predicate = parse("data.ok == true && name == A || name == B");
+
+        assert!(predicate.matches("B", &json!({})));
+        assert!(!predicate.matches("A", &json!({ "ok": false })));
+    }
+
+    #[test]
+    fn values() {
+        let data = json!({ "n": 1.0, "s": "open", "b": false, "list": [{ "id": "x" }] });
+
+        assert!(parse("data.n == 1").matches("", &data));
+        assert!(parse(r#"data.s == "open""#).matches("", &data));
+        assert!(parse("data.s == open").matches("", &data));
+        assert!(parse("data.b == false").matches("", &data));
+        assert!(parse("data.missing == null").matches("", &data));
+        assert!(parse("data.list.0.id == x").matches("", &data));
+        assert!(parse(r#"data.s != "closed""#).matches("", &data));
+        assert!(parse(r#"name in ["A B", C]"#).matches("A B", &data));
+        assert!(!parse("name in []").matches("A", &data));
+    }
+
+    #[test]
+    fn escaped_string() {
+        let predicate = parse(r#"data.s == "a \"b\" \\ c""#);
+
+        assert!(predicate.matches("", &json!({ "s": r#"a "b" \ c"# })));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(""), "invalid predicate: unexpected end");
+        assert_eq!(
+            error("title == A"),
+            "invalid predicate: unknown field `title`"
+        );
+        assert_eq!(error("name = A"), "invalid predicate: unexpected `=`");
+        assert_eq!(error("name == A &&"), "invalid predicate: unexpected end");
+        assert_eq!(
+            error("name == A name == B"),
+            "invalid predicate: unexpected `name`"
+        );
+        assert_eq!(error("name in A"), "invalid predicate: unexpected `A`");
+        assert_eq!(error("name in [A B]"), "invalid predicate: unexpected `B`");
+        assert_eq!(error("name in [A,"), "invalid predicate: unexpected end");
+        assert_eq!(
+            error(r#"name == "A"#),
+            "invalid predicate: unterminated string"
+        );
+        assert_eq!(error("== A"), "invalid predicate: unexpected `==`");
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+/// Number of sessions each user holds per subscribed topic.
+#[derive(Debug, Default)]
+pub struct Presence {
+    topics: HashMap<String, HashMap<String, usize>>,
+}
+
+impl Presence {
+    /// Returns true when it is the first session of `user_id` on `topic`.
+    pub fn join(&mut self, topic: &str, user_id: &str) -> bool {
+        let count = self
+            .topics
+            .entry(topic.to_owned())
+            .or_default()
+            .entry(user_id.to_owned())
+            .or_default();
+
+        *count += 1;
+
+        *count == 1
+    }
+
+    /// Returns true when it was the last session of `user_id` on `topic`.
+    pub fn leave(&mut self, topic: &str, user_id: &str) -> bool {
+        let Some(users) = self.topics.get_mut(topic) else {
+            return false;
+        };
+
+        let Some(count) = users.get_mut(user_id) else {
+            return false;
+        };
+
+        *count -= 1;
+
+        if *count > 0 {
+            return false;
+        }
+
+        users.remove(user_id);
+
+        if users.is_empty() {
+            self.topics.remove(topic);
+        }
+
+        true
+    }
+
+    /// Users with at least one session subscribed to `topic`, sorted.
+    pub fn users(&self, topic: &str) -> Vec<String> {
+        let mut users = self
+            .topics
+            .get(topic)
+            .map(|users| users.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        users.sort();
+
+        users
+    }
+}
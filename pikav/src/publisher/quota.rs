@@ -0,0 +1,45 @@
+use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits enforced by the publisher, `None` is unlimited.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct Quotas {
+    pub sessions_per_user: Option<usize>,
+    pub filters_per_session: Option<usize>,
+    pub subscribes_per_minute: Option<u32>,
+}
+
+/// Subscribe calls of each user in its current one minute window.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    windows: HashMap<String, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    /// Counts a call of `user_id`, false once it made more than `max` calls in the window.
+    pub fn hit(&mut self, user_id: &str, max: u32) -> bool {
+        let now = Instant::now();
+        let (started_at, count) = self.windows.entry(user_id.to_owned()).or_insert((now, 0));
+
+        if now.duration_since(*started_at) >= WINDOW {
+            *started_at = now;
+            *count = 0;
+        }
+
+        if *count >= max {
+            return false;
+        }
+
+        *count += 1;
+
+        true
+    }
+
+    pub fn purge(&mut self) {
+        self.windows
+            .retain(|_, (started_at, _)| started_at.elapsed() < WINDOW);
+    }
+}
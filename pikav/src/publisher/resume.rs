@@ -0,0 +1,59 @@
+use hmac::{Hmac, Mac};
+use nanoid::nanoid;
+use sha2::Sha256;
+use std::fmt::Write;
+
+/// Signs the resume tokens, `{session id}.{nonce}.{signature}`, with a key of
+/// this node. Suspended sessions only live on the node they were connected to.
+#[derive(Clone)]
+pub(crate) struct ResumeTokens {
+    mac: Hmac<Sha256>,
+}
+
+impl Default for ResumeTokens {
+    fn default() -> Self {
+        Self {
+            mac: Hmac::new_from_slice(nanoid!(32).as_bytes()).expect("HMAC takes keys of any size"),
+        }
+    }
+}
+
+impl ResumeTokens {
+    /// A new token for each connection of the session, the nonce makes the
+    /// token of a previous connection useless once the session is resumed.
+    pub fn sign(&self, id: &str) -> String {
+        let payload = format!("{id}.{}", nanoid!());
+        let signature = self.mac(&payload).finalize().into_bytes();
+
+        let mut token = payload;
+        token.push('.');
+
+        for byte in signature {
+            let _ = write!(token, "{byte:02x}");
+        }
+
+        token
+    }
+
+    /// Session id of `token` when this node signed it.
+    pub fn verify<'a>(&self, token: &'a str) -> Option<&'a str> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let (id, _) = payload.split_once('.')?;
+
+        let signature = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+
+        self.mac(payload).verify_slice(&signature).ok()?;
+
+        Some(id)
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = self.mac.clone();
+        mac.update(payload.as_bytes());
+
+        mac
+    }
+}
@@ -0,0 +1,411 @@
+use glob_match::glob_match;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+const MAX_LEN: usize = 1024;
+
+const GLOB: [char; 7] = ['*', '?', '[', ']', '{', '}', '\\'];
+
+/// How the wildcards of a filter are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterSyntax {
+    /// `*` for a segment, `**` for any number of them, `{a,b}`, `[ab]`, `?`.
+    Glob,
+    /// `+` for a segment, a last `#` for any number of them, the parent
+    /// level included.
+    Mqtt,
+}
+
+impl fmt::Display for FilterSyntax {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterSyntax::Glob => f.write_str("glob"),
+            FilterSyntax::Mqtt => f.write_str("mqtt"),
+        }
+    }
+}
+
+/// The topic of an event, segments separated by `/` without any wildcard,
+/// e.g. `todos/1`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Topic(String);
+
+impl Topic {
+    pub fn new(value: impl Into<String>) -> Result<Self, TopicError> {
+        let value = value.into();
+
+        check_segments(&value)?;
+
+        if let Some(c) = value.chars().find(|c| GLOB.contains(c)) {
+            return Err(TopicError::Wildcard(c));
+        }
+
+        let wildcard = value.split('/').find_map(|segment| match segment {
+            "+" => Some('+'),
+            "#" => Some('#'),
+            _ => None,
+        });
+
+        if let Some(c) = wildcard {
+            return Err(TopicError::Wildcard(c));
+        }
+
+        Ok(Self(value))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.0.split('/')
+    }
+}
+
+/// A filter matching topics, either a glob, e.g. `todos/*` or `users/42/**`,
+/// or MQTT wildcards, e.g. `todos/+` or `users/42/#`. Both can't be mixed.
+///
+/// At least one segment must not be a wildcard, `**` alone would receive
+/// every event. Topics starting with `$`, e.g. `$SYS/presence/todos`, are
+/// only matched by filters starting with `$` too.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TopicFilter(String);
+
+impl TopicFilter {
+    pub fn new(value: impl Into<String>) -> Result<Self, TopicError> {
+        let value = value.into();
+
+        check_segments(&value)?;
+
+        if syntax_of(&value) == Some(FilterSyntax::Mqtt) {
+            check_mqtt(&value)?;
+        }
+
+        for (open, close) in [('[', ']'), ('{', '}')] {
+            let mut depth = 0i32;
+
+            for c in value.chars() {
+                match c {
+                    c if c == open => depth += 1,
+                    c if c == close => depth -= 1,
+                    _ => {}
+                }
+
+                if depth < 0 {
+                    return Err(TopicError::Unbalanced(close));
+                }
+            }
+
+            if depth != 0 {
+                return Err(TopicError::Unbalanced(open));
+            }
+        }
+
+        if value
+            .split('/')
+            .all(|segment| matches!(segment, "*" | "**" | "+" | "#"))
+        {
+            return Err(TopicError::TooBroad);
+        }
+
+        Ok(Self(value))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.0.split('/')
+    }
+
+    /// `None` for a filter without any wildcard, valid in both syntaxes.
+    pub fn syntax(&self) -> Option<FilterSyntax> {
+        syntax_of(&self.0)
+    }
+
+    pub fn matches(&self, topic: &Topic) -> bool {
+        filter_match(&self.0, &topic.0)
+    }
+}
+
+/// Whether `filter` matches `topic`, with the syntax the filter is written in.
+pub(crate) fn filter_match(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && !filter.starts_with('$') {
+        return false;
+    }
+
+    match syntax_of(filter) {
+        Some(FilterSyntax::Mqtt) => mqtt_match(filter, topic),
+        _ => glob_match(filter, topic),
+    }
+}
+
+/// The start every topic matched by `filter` shares, e.g. `todos/` for
+/// `todos/*` or `todos` for `todos/#`, empty for `+/todos`.
+#[cfg(feature = "sqlite")]
+pub(crate) fn literal_prefix(filter: &str) -> &str {
+    let end = match syntax_of(filter) {
+        Some(FilterSyntax::Mqtt) => {
+            let mut start = 0;
+
+            for segment in filter.split('/') {
+                if matches!(segment, "+" | "#") {
+                    break;
+                }
+
+                start += segment.len() + 1;
+            }
+
+            start.saturating_sub(1)
+        }
+        _ => filter.find(GLOB).unwrap_or(filter.len()),
+    };
+
+    &filter[..end]
+}
+
+fn mqtt_match(filter: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+
+    for segment in filter.split('/') {
+        match (segment, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (segment, Some(level)) if segment == level => {}
+            _ => return false,
+        }
+    }
+
+    topic.next().is_none()
+}
+
+pub(crate) fn syntax_of(value: &str) -> Option<FilterSyntax> {
+    if value.split('/').any(|segment| matches!(segment, "+" | "#")) {
+        return Some(FilterSyntax::Mqtt);
+    }
+
+    match value.contains(GLOB) {
+        true => Some(FilterSyntax::Glob),
+        false => None,
+    }
+}
+
+fn check_mqtt(value: &str) -> Result<(), TopicError> {
+    if value.contains(GLOB) {
+        return Err(TopicError::MixedSyntax);
+    }
+
+    let mut segments = value.split('/').peekable();
+
+    while let Some(segment) = segments.next() {
+        if segment == "#" && segments.peek().is_some() {
+            return Err(TopicError::MultiLevelNotLast);
+        }
+
+        if segment.len() > 1 {
+            if let Some(c) = segment.chars().find(|c| matches!(c, '+' | '#')) {
+                return Err(TopicError::PartialSegment(c));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_segments(value: &str) -> Result<(), TopicError> {
+    if value.is_empty() {
+        return Err(TopicError::Empty);
+    }
+
+    if value.len() > MAX_LEN {
+        return Err(TopicError::TooLong(MAX_LEN));
+    }
+
+    if value.split('/').any(str::is_empty) {
+        return Err(TopicError::EmptySegment);
+    }
+
+    match value.chars().find(|c| c.is_control()) {
+        Some(c) => Err(TopicError::Control(c)),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicError {
+    Empty,
+    /// Longer than this many bytes.
+    TooLong(usize),
+    /// A leading, trailing or double `/`.
+    EmptySegment,
+    Control(char),
+    /// A glob character, or a `+` or `#` segment, in a topic.
+    Wildcard(char),
+    /// MQTT wildcards and glob characters in the same filter.
+    MixedSyntax,
+    /// A `#` followed by other segments.
+    MultiLevelNotLast,
+    /// A `+` or `#` sharing its segment with other characters.
+    PartialSegment(char),
+    /// A `[` or `{` without its closing pair, or the other way around.
+    Unbalanced(char),
+    /// A filter made only of wildcard segments.
+    TooBroad,
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicError::Empty => f.write_str("topic is empty"),
+            TopicError::TooLong(max) => write!(f, "topic is longer than {max} bytes"),
+            TopicError::EmptySegment => f.write_str("topic has an empty segment"),
+            TopicError::Control(c) => write!(f, "topic contains the control character {c:?}"),
+            TopicError::Wildcard(c) => write!(f, "topic contains the wildcard `{c}`"),
+            TopicError::MixedSyntax => f.write_str("filter mixes mqtt and glob wildcards"),
+            TopicError::MultiLevelNotLast => {
+                f.write_str("filter has a `#` before its last segment")
+            }
+            TopicError::PartialSegment(c) => {
+                write!(f, "filter has a `{c}` that is not a whole segment")
+            }
+            TopicError::Unbalanced(c) => write!(f, "filter has an unbalanced `{c}`"),
+            TopicError::TooBroad => f.write_str("filter has no segment without a wildcard"),
+        }
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+impl FromStr for Topic {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for Topic {
+    type Error = TopicError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+impl From<Topic> for String {
+    fn from(value: Topic) -> Self {
+        value.0
+    }
+}
+
+impl AsRef<str> for Topic {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for TopicFilter {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for TopicFilter {
+    type Error = TopicError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+impl From<TopicFilter> for String {
+    fn from(value: TopicFilter) -> Self {
+        value.0
+    }
+}
+
+impl AsRef<str> for TopicFilter {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_level() {
+        assert!(filter_match("a/#", "a"));
+        assert!(filter_match("a/#", "a/b"));
+        assert!(filter_match("a/#", "a/b/c"));
+        assert!(!filter_match("a/#", "b"));
+        assert!(!filter_match("a/#", "ab"));
+        assert!(filter_match("a/+/#", "a/b"));
+        assert!(!filter_match("a/+/#", "a"));
+    }
+
+    #[test]
+    fn single_level() {
+        assert!(filter_match("a/+", "a/b"));
+        assert!(!filter_match("a/+", "a"));
+        assert!(!filter_match("a/+", "a/b/c"));
+        assert!(filter_match("+/b/+", "a/b/c"));
+        assert!(!filter_match("+/b", "a/c"));
+    }
+
+    #[test]
+    fn sys_topics() {
+        for filter in ["#", "+/presence/todos", "*/presence/todos", "**/todos"] {
+            assert!(!filter_match(filter, "$SYS/presence/todos"), "{filter}");
+        }
+
+        assert!(filter_match("$SYS/#", "$SYS/presence/todos"));
+        assert!(filter_match("$SYS/presence/+", "$SYS/presence/todos"));
+        assert!(filter_match("$SYS/**", "$SYS/presence/todos"));
+        assert!(filter_match("+/presence/todos", "SYS/presence/todos"));
+    }
+
+    #[test]
+    fn syntax() {
+        let syntax = |filter: &str| filter.parse::<TopicFilter>().unwrap().syntax();
+
+        assert_eq!(syntax("a/b"), None);
+        assert_eq!(syntax("a/*"), Some(FilterSyntax::Glob));
+        assert_eq!(syntax("a/+"), Some(FilterSyntax::Mqtt));
+        assert_eq!(syntax("a/#"), Some(FilterSyntax::Mqtt));
+        assert_eq!(syntax("a+/b#"), None);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |filter: &str| filter.parse::<TopicFilter>().unwrap_err();
+
+        assert_eq!(error("a/+/*"), TopicError::MixedSyntax);
+        assert_eq!(error("a/#/b"), TopicError::MultiLevelNotLast);
+        assert_eq!(error("a/+/b#"), TopicError::PartialSegment('#'));
+        assert_eq!(error("+/#"), TopicError::TooBroad);
+        assert_eq!(error("#"), TopicError::TooBroad);
+        assert_eq!("a/+".parse::<Topic>(), Err(TopicError::Wildcard('+')));
+        assert_eq!("a/#".parse::<Topic>(), Err(TopicError::Wildcard('#')));
+        assert!("a/b+".parse::<Topic>().is_ok());
+    }
+}